@@ -8,33 +8,38 @@
 
 #![warn(missing_debug_implementations)]
 
-use camino::Utf8PathBuf;
-use cargo_metadata::Metadata;
+use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata::MetadataCommand;
-use ninja_files::format::write_ninja_file;
-use ninja_files_data::{File, FileBuilder};
+use cargo_metadata::{Metadata, Package};
+use ninja_files_data::{BuildBuilder, FileBuilder};
 use serde::de;
 use serde::de::Error;
+use serde::ser;
 use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::io::Read;
 use std::string::ToString;
 use std::{
     collections::{BTreeMap, BTreeSet},
     sync::{LazyLock, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use crate::cli;
 use crate::cli::args_for_cargo;
 use crate::crate_type::CrateType;
-use crate::custom_build::BuildScriptOutput;
-use crate::rustc_config::rustc;
+use crate::path_rewrite::PathRewrite;
+use crate::rustc_config::{rustc, rustdoc};
+use crate::unit_graph::{package_env, UnitGraph};
+use crate::{ninja_bench_compare, ninja_run_test_cases, TESTS_NINJA};
 
-static METADATA: LazyLock<Metadata> = LazyLock::new(|| match MetadataCommand::new().exec() {
-    Ok(d) => d,
-    Err(e) => panic!("Metadata Command failed: {e:?}"),
-});
+pub(crate) static METADATA: LazyLock<Metadata> =
+    LazyLock::new(|| match MetadataCommand::new().exec() {
+        Ok(d) => d,
+        Err(e) => panic!("Metadata Command failed: {e:?}"),
+    });
 
 #[allow(dead_code)]
 #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -72,6 +77,23 @@ impl<'de> de::Deserialize<'de> for TargetKind {
     }
 }
 
+impl ser::Serialize for TargetKind {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        use self::TargetKind::*;
+        match self {
+            Lib(kinds) => kinds.serialize(s),
+            Bin => ["bin"].serialize(s),
+            ExampleBin | ExampleLib(_) => ["example"].serialize(s),
+            Test => ["test"].serialize(s),
+            CustomBuild => ["custom-build"].serialize(s),
+            Bench => ["bench"].serialize(s),
+        }
+    }
+}
+
 impl fmt::Debug for TargetKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::TargetKind::*;
@@ -176,6 +198,15 @@ impl fmt::Display for CompileMode {
     }
 }
 
+impl ser::Serialize for CompileMode {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        self.to_string().serialize(s)
+    }
+}
+
 impl<'de> de::Deserialize<'de> for CompileMode {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -202,7 +233,7 @@ impl<'de> de::Deserialize<'de> for CompileMode {
 }
 
 /// A tool invocation.
-#[derive(Debug, Deserialize, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Invocation {
     pub package_name: String,
     pub package_version: String,
@@ -237,6 +268,18 @@ impl Invocation {
         self.compile_mode == CompileMode::RunCustomBuild
     }
 
+    pub fn is_doc(&self) -> bool {
+        matches!(self.compile_mode, CompileMode::Doc { .. })
+    }
+
+    /// Whether the program is `rustdoc` rather than `rustc`.
+    pub fn is_rustdoc(&self) -> bool {
+        matches!(
+            self.compile_mode,
+            CompileMode::Doc { .. } | CompileMode::Doctest | CompileMode::Docscrape
+        )
+    }
+
     pub fn is_workspace_build(&self) -> bool {
         let workspace_packages = METADATA.workspace_packages();
 
@@ -279,27 +322,200 @@ impl Invocation {
             .ok_or(anyhow::format_err!("failed to find extra-filename in args"))
     }
 
+    /// The value following `flag` in the rustc arguments.
+    fn arg_value(&self, flag: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| self.args.get(i + 1))
+            .map(String::as_str)
+    }
+
+    /// Every value following `flag` in the rustc arguments.
+    fn arg_values<'a>(&'a self, flag: &'a str) -> impl Iterator<Item = &'a str> {
+        self.args
+            .windows(2)
+            .filter(move |pair| pair[0] == flag)
+            .map(|pair| pair[1].as_str())
+    }
+
+    pub fn crate_name(&self) -> anyhow::Result<&str> {
+        self.arg_value("--crate-name")
+            .ok_or(anyhow::format_err!("failed to find --crate-name in args"))
+    }
+
+    fn package(&self) -> Option<&'static Package> {
+        METADATA
+            .packages
+            .iter()
+            .find(|p| p.name == self.package_name && p.version.to_string() == self.package_version)
+    }
+
+    /// The `links` manifest key of the package.
+    pub fn manifest_links(&self) -> Option<String> {
+        self.package().and_then(|p| p.links.clone())
+    }
+
+    /// The environment cargo runs the targets of the package with.
+    pub fn runtime_env(&self) -> BTreeMap<String, String> {
+        self.package().map(package_env).unwrap_or_default()
+    }
+
+    /// The package root, which cargo runs tests, benches and doctests from.
+    pub fn manifest_dir(&self) -> Option<Utf8PathBuf> {
+        self.env
+            .get("CARGO_MANIFEST_DIR")
+            .map(Utf8PathBuf::from)
+            .or_else(|| self.cwd.clone())
+    }
+
+    /// The `ninja run-<bin>` or `ninja run-example-<name>` target running
+    /// this binary, like `cargo run`. It is `run-<package>:<bin>` when
+    /// `qualified`, for a binary name which several packages have.
+    pub fn run_target(&self, qualified: bool) -> Option<String> {
+        if self.compile_mode != CompileMode::Build || !self.is_executable() {
+            return None;
+        }
+        let name = self.target_name().ok()?;
+        let name = match qualified {
+            // `:` separates the outputs of a build statement
+            true => format!("{}$:{name}", self.package_name),
+            false => name,
+        };
+        let prefix = self.triple_prefix();
+        match self.is_exe_example() {
+            true => Some(format!("{prefix}run-example-{name}")),
+            false => Some(format!("{prefix}run-{name}")),
+        }
+    }
+
+    /// The `--target` triple this compiles for, `None` for the host units.
+    pub fn target_triple(&self) -> Option<&str> {
+        self.arg_values("--target").next()
+    }
+
+    /// `<triple>:` with several `--target`s, which prefixes the phony targets
+    /// of this invocation that would otherwise clash between the triples.
+    fn triple_prefix(&self) -> String {
+        match self.target_triple() {
+            // `:` separates the outputs of a build statement
+            Some(triple) if cli::targets().len() > 1 => format!("{triple}$:"),
+            _ => String::new(),
+        }
+    }
+
+    /// The `.rmeta` written along with the rlib, which a separate edge
+    /// outputs as soon as rustc wrote it, see `emit_metadata`.
+    pub fn pipelined_metadata(&self) -> Option<Utf8PathBuf> {
+        let rlib = self
+            .outputs
+            .iter()
+            .find(|o| o.extension() == Some("rlib"))?;
+        let rmeta = rlib.with_extension("rmeta");
+        (!self.is_rustdoc() && self.outputs.contains(&rmeta)).then_some(rmeta)
+    }
+
+    /// Whether this links the objects of its dependencies, like cargo's
+    /// `mode.is_any_test() || kind.requires_upstream_objects()`. Otherwise
+    /// the `.rmeta` of the dependencies is enough, as for documenting any
+    /// target.
+    pub fn requires_upstream_objects(&self) -> bool {
+        !self.is_doc() && (self.is_test_build() || self.target_kind.requires_upstream_objects())
+    }
+
+    /// Whether this compiles the `--test` harness, of a test, bench or the
+    /// unit tests of another target.
+    pub fn is_test_build(&self) -> bool {
+        matches!(
+            self.compile_mode,
+            CompileMode::Test | CompileMode::Bench | CompileMode::Check { test: true }
+        )
+    }
+
+    /// The outputs and their uplifted links.
+    pub fn products(&self) -> Vec<Utf8PathBuf> {
+        let mut products = self.outputs();
+        products.extend(self.links().into_keys());
+        products
+    }
+
+    /// The `ninja bench-<name>` target running this bench binary, or
+    /// `bench-<package>:<name>` when `qualified`, like [`Self::run_target`].
+    pub fn bench_target(&self, qualified: bool) -> Option<String> {
+        let compiled = matches!(self.compile_mode, CompileMode::Test | CompileMode::Bench);
+        if !compiled || !self.is_bench() {
+            return None;
+        }
+        let name = self.target_name().ok()?;
+        let name = match qualified {
+            true => format!("{}$:{name}", self.package_name),
+            false => name,
+        };
+        Some(format!("{}bench-{name}", self.triple_prefix()))
+    }
+
+    /// Where the results of the bench binary are saved, by package and
+    /// target name so the baseline outlives rebuilds. Next to the `deps` dir
+    /// of the binary to keep the profiles apart.
+    pub fn bench_dir(&self) -> Option<Utf8PathBuf> {
+        let name = self.target_name().ok()?;
+        let binary = self.outputs().into_iter().next()?;
+        let dir = binary.parent()?.parent()?.join("bench");
+        Some(dir.join(&self.package_name).join(name))
+    }
+
+    /// The uplifted binary `cargo run` runs, or the output when there is none.
+    pub fn executable(&self) -> Option<Utf8PathBuf> {
+        self.links()
+            .into_keys()
+            .next()
+            .or_else(|| self.outputs().into_iter().next())
+    }
+
+    /// The name of the target as in the manifest, e.g. `[[bin]] name`, which
+    /// `--crate-name` has with `-` replaced by `_`.
+    pub fn target_name(&self) -> anyhow::Result<String> {
+        let crate_name = self.crate_name()?;
+        // a lib and a bin may share the crate name
+        let kind = serde_json::to_value(&self.target_kind).ok();
+        let name = METADATA
+            .packages
+            .iter()
+            .filter(|p| {
+                p.name == self.package_name && p.version.to_string() == self.package_version
+            })
+            .flat_map(|p| &p.targets)
+            .find(|t| {
+                t.name.replace('-', "_") == crate_name && serde_json::to_value(&t.kind).ok() == kind
+            })
+            .map_or(crate_name.to_string(), |t| t.name.clone());
+        Ok(name)
+    }
+
+    /// The dep-info file rustc writes next to the outputs, see `--emit=dep-info`.
+    ///
+    /// For build script runs, the depfile of the files the script watches.
     pub fn dep_info_file(&self) -> anyhow::Result<Utf8PathBuf> {
-        let extra_filename = self.extra_filename()?;
-        let build_dir = build_dir()?;
-        let package_name = self.package_name.clone().replace("-", "_");
-        match (&self.target_kind, self.compile_mode) {
-            (TargetKind::CustomBuild, CompileMode::Build) => {
-                let file = format!(
-                    "build/{package_name}{extra_filename}/build_script_build{extra_filename}.d"
-                );
-                let file = build_dir.join(file);
-                Ok(file)
-            }
-            (TargetKind::CustomBuild, CompileMode::RunCustomBuild) => {
-                Err(anyhow::format_err!("todo"))
-            }
-            _ => {
-                let file = format!("deps/{package_name}{extra_filename}.d");
-                let file = build_dir.join(file);
-                Ok(file)
-            }
+        if self.is_run_custom_build() {
+            // written by the `build-script` tool from `rerun-if-changed`
+            return Ok(self.build_script_output_file()?.with_extension("d"));
+        }
+        // rustdoc only writes dep-info to an explicit path
+        let emit_path = self
+            .args
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("--emit="))
+            .flat_map(|emit| emit.split(','))
+            .find_map(|kind| kind.strip_prefix("dep-info="));
+        if let Some(path) = emit_path {
+            return Ok(Utf8PathBuf::from(path));
         }
+        let out_dir = self
+            .arg_value("--out-dir")
+            .ok_or(anyhow::format_err!("failed to find --out-dir in args"))?;
+        let crate_name = self.crate_name()?;
+        let extra_filename = self.extra_filename()?;
+        Ok(Utf8PathBuf::from(out_dir).join(format!("{crate_name}{extra_filename}.d")))
     }
 
     pub fn build_script_output_file(&self) -> anyhow::Result<Utf8PathBuf> {
@@ -310,29 +526,136 @@ impl Invocation {
             .join("output"))
     }
 
-    pub fn build_script_output(&self) -> anyhow::Result<BuildScriptOutput> {
-        let file = self.build_script_output_file()?;
-        let file = file.into_std_path_buf();
-        if !file.exists() {
-            //TODO how to run these in parallel
-            run_build_script(&self)?;
-        }
-        // We currently using the same out_dir for rus_custom_build and build
-        let dir = &file
+    /// The values of the build script's `rerun-if-env-changed` variables, see
+    /// the `env-check` tool.
+    pub fn env_snapshot_file(&self) -> anyhow::Result<Utf8PathBuf> {
+        Ok(self.build_script_output_file()?.with_file_name("env"))
+    }
+
+    /// The file the doctest edge of this library touches once its doctests
+    /// passed, next to the `deps` directory.
+    pub fn doctest_stamp(&self) -> anyhow::Result<Utf8PathBuf> {
+        let out_dir = self
+            .arg_value("--out-dir")
+            .ok_or(anyhow::format_err!("failed to find --out-dir in args"))?;
+        let out_dir = Utf8Path::new(out_dir);
+        let crate_name = self.crate_name()?;
+        let extra_filename = self.extra_filename()?;
+        Ok(out_dir
             .parent()
-            .ok_or(anyhow::format_err!("failed to get output dir"))?;
-        BuildScriptOutput::parse_file(
-            file.as_path(),
-            Some(self.package_name.clone()),
-            &self.package_name,
-            dir,
-            dir,
-            true,
-            true,
-            &None,
+            .unwrap_or(out_dir)
+            .join("doctest")
+            .join(format!("{crate_name}{extra_filename}.stamp")))
+    }
+
+    /// The libtest binary of a `--test` compilation, not those of targets
+    /// with `harness = false`.
+    pub fn test_binary(&self) -> Option<Utf8PathBuf> {
+        (self.compile_mode == CompileMode::Test && self.args.iter().any(|arg| arg == "--test"))
+            .then(|| self.outputs().into_iter().next())
+            .flatten()
+    }
+
+    /// The JSON events of the last run of the test binary, next to it.
+    pub fn test_output(&self) -> Option<Utf8PathBuf> {
+        self.test_binary().map(|b| b.with_extension("test.json"))
+    }
+
+    /// The file touched once every test of the binary passed, next to it.
+    pub fn test_stamp(&self) -> Option<Utf8PathBuf> {
+        self.test_binary().map(|b| b.with_extension("test.stamp"))
+    }
+
+    /// The ninja fragment with an edge per test of the binary, see
+    /// `--test-cases`.
+    pub fn test_cases_fragment(&self) -> Option<Utf8PathBuf> {
+        self.test_binary().map(|b| b.with_extension("test.ninja"))
+    }
+
+    /// Identifies the test binary across builds for `--test-shards`, unlike
+    /// its file name which changes with the metadata hash.
+    pub fn test_shard_key(&self) -> String {
+        let target_name = self.target_name().unwrap_or_default();
+        format!(
+            "{} {} {target_name}",
+            self.package_name,
+            self.target_kind.description()
         )
     }
 
+    /// The phony over the test cases of `shard` listed in the fragment, see
+    /// `--test-cases`.
+    pub fn test_shard_stamp(&self, shard: usize) -> Option<Utf8PathBuf> {
+        self.test_binary()
+            .map(|b| b.with_extension(format!("test.shard-{shard}")))
+    }
+
+    /// The `--extern NAME=PATH` values of the compilation, with metadata-only
+    /// externs replaced by their rlib, see `emit_metadata`.
+    pub fn linked_externs(&self) -> Vec<(String, Utf8PathBuf)> {
+        self.arg_values("--extern")
+            .filter_map(|arg| arg.split_once('='))
+            .map(|(name, path)| {
+                let path = Utf8PathBuf::from(path);
+                let path = match path.extension() {
+                    Some("rmeta") => path.with_extension("rlib"),
+                    _ => path,
+                };
+                (name.to_string(), path)
+            })
+            .collect()
+    }
+
+    /// The `rustdoc --test` arguments which run the doctests of this library
+    /// like cargo's `run_doc_tests`, linking `externs` and the library itself.
+    pub fn doctest_args(&self, externs: &[(String, Utf8PathBuf)]) -> anyhow::Result<Vec<String>> {
+        let args = self.args();
+        let fake_args = std::iter::once("rustc".to_string()).chain(args.iter().cloned());
+        let matches = rustc().get_matches_from(fake_args);
+        let input = matches
+            .get_one::<Utf8PathBuf>("INPUT")
+            .ok_or(anyhow::format_err!("failed to find the input in args"))?;
+        let crate_name = self.crate_name()?;
+        let lib = self
+            .outputs()
+            .into_iter()
+            .find(|o| o.extension() != Some("rmeta"))
+            .ok_or(anyhow::format_err!(
+                "failed to find the library of {crate_name}"
+            ))?;
+
+        let mut doctest_args = vec!["--test".to_string(), input.to_string()];
+        doctest_args.extend(
+            args.iter()
+                .filter(|arg| arg.starts_with("--edition="))
+                .cloned(),
+        );
+        for flag in [
+            "--crate-name",
+            "--crate-type",
+            "--cfg",
+            "--check-cfg",
+            "-L",
+            "--target",
+        ] {
+            for value in self.arg_values(flag) {
+                doctest_args.push(flag.to_string());
+                doctest_args.push(value.to_string());
+            }
+        }
+        // cargo runs doctests from the package root
+        if let Some(dir) = self.manifest_dir() {
+            doctest_args.push("--test-run-directory".to_string());
+            doctest_args.push(dir.to_string());
+        }
+        let own = (crate_name.to_string(), lib);
+        for (name, path) in std::iter::once(&own).chain(externs) {
+            doctest_args.push("--extern".to_string());
+            doctest_args.push(format!("{name}={path}"));
+        }
+        Ok(doctest_args)
+    }
+
     pub fn outputs(&self) -> Vec<Utf8PathBuf> {
         let outputs = if self.compile_mode == CompileMode::RunCustomBuild {
             vec![self
@@ -451,7 +774,10 @@ impl Invocation {
                         acc.push(arg);
                         acc
                     });
-            let matches = rustc().get_matches_from(fake_args);
+            let matches = match self.is_rustdoc() {
+                true => rustdoc().get_matches_from(fake_args),
+                false => rustc().get_matches_from(fake_args),
+            };
             let orig_input = matches.get_one::<Utf8PathBuf>("INPUT");
             let input = cwd.as_ref().zip_with(orig_input, |cwd, i| cwd.join(i));
             let input = input
@@ -479,6 +805,25 @@ impl Invocation {
         self.args.clone()
     }
 
+    /// Applies `rewrite` to every path this invocation refers to.
+    pub fn rewrite_paths(&mut self, rewrite: &PathRewrite) {
+        let path = |p: &Utf8PathBuf| rewrite.path(p);
+        self.outputs = self.outputs.iter().map(path).collect();
+        self.links = self
+            .links
+            .iter()
+            .map(|(link, target)| (path(link), path(target)))
+            .collect();
+        self.program = rewrite.arg(&self.program);
+        self.args = self.args.iter().map(|arg| rewrite.arg(arg)).collect();
+        self.env = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), rewrite.env(value)))
+            .collect();
+        self.cwd = self.cwd.as_ref().map(path);
+    }
+
     pub(crate) fn cwd(&self) -> Option<Utf8PathBuf> {
         if self.is_workspace_build() {
             return build_dir().ok();
@@ -488,7 +833,7 @@ impl Invocation {
 }
 
 /// A build plan output by `cargo build --build-plan`.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct BuildPlan {
     /// Program invocations needed to build the target (along with dependency information).
     pub invocations: Vec<Invocation>,
@@ -501,118 +846,828 @@ impl BuildPlan {
     ///
     /// Build plan output can be obtained by running `cargo build --build-plan`. Generating build
     /// plans for individual targets (tests, examples, etc.) also works.
-    pub fn from_cargo_output() -> anyhow::Result<Self> {
-        let mut cmd = std::process::Command::new("cargo");
-        if let Ok(dir) = std::env::current_dir() {
-            cmd.current_dir(dir);
-        }
-        args_for_cargo().into_iter().for_each(|arg| {
-            cmd.arg(arg);
-        });
-        cmd.envs(std::env::vars());
+    pub fn from_cargo_output(
+        profile: Option<&str>,
+        selection: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut cmd = cargo_command(&plan_args(
+            &["-Zunstable-options", cargo_subcommand(), "--build-plan"],
+            profile,
+            selection,
+        ));
 
-        let build_dir = build_dir()?;
-        cmd.env("CARGO_TARGET_DIR", build_dir.as_str());
+        let build_dir = profile_build_dir(profile)?;
+        // cargo creates the profile dirs when asked for a build plan, keep it
+        // out of the build dir, which a running `ninja` may be using
+        let scratch = ScratchDir::new()?;
+        cmd.env("CARGO_TARGET_DIR", scratch.path().as_str());
 
         let output = cmd.output().expect("failed to execute process");
 
         if output.status.success() {
-            let mut data = output.stdout;
-            let output = String::from_utf8(data.clone())?;
-            let output = output
-                .replace(build_dir.join("debug").as_str(), build_dir.as_str())
-                .replace(build_dir.join("release").as_str(), build_dir.as_str());
-            data = output.into_bytes();
-            // these dirs are created when invoke cargo build --build-plan
-            let cargo_debug_dir = build_dir.join("debug");
-            if cargo_debug_dir.exists() {
-                std::fs::remove_dir_all(cargo_debug_dir)?;
-            }
-            let cargo_release_dir = build_dir.join("release");
-            if cargo_release_dir.exists() {
-                std::fs::remove_dir_all(cargo_release_dir)?;
-            }
+            let mut plan: BuildPlan = serde_json::from_slice(output.stdout.as_ref())?;
+            plan.normalize(&build_dir, cli::cargo_layout());
+            return Ok(plan);
+        }
+        let error = String::from_utf8(output.stderr)?;
+        Err(anyhow::format_err!("{error}"))
+    }
 
-            let plan = serde_json::from_slice(data.as_ref())?;
+    /// Builds a `BuildPlan` from Cargo's `--unit-graph` output.
+    ///
+    /// The unit graph carries no command lines, these are rebuilt from the unit
+    /// data by [`UnitGraph::into_build_plan`].
+    pub fn from_unit_graph(profile: Option<&str>, selection: Option<&str>) -> anyhow::Result<Self> {
+        let mut cmd = cargo_command(&plan_args(
+            &["-Zunstable-options", cargo_subcommand(), "--unit-graph"],
+            profile,
+            selection,
+        ));
+        let output = cmd.output().expect("failed to execute process");
 
-            return Ok(plan);
+        if output.status.success() {
+            let graph: UnitGraph = serde_json::from_slice(output.stdout.as_ref())?;
+            return graph.into_build_plan(&profile_build_dir(profile)?);
         }
         let error = String::from_utf8(output.stderr)?;
         Err(anyhow::format_err!("{error}"))
     }
 
+    /// Runs cargo for the plan of `profile` and the target `selection`, from
+    /// `--build-plan` unless `--unit-graph` is given or cargo lacks it.
+    fn from_cargo(profile: Option<&str>, selection: Option<&str>) -> anyhow::Result<Self> {
+        if cli::unit_graph() {
+            return BuildPlan::from_unit_graph(profile, selection);
+        }
+        if !cargo_has_build_plan() {
+            eprintln!("warning: cargo has no `--build-plan`, falling back to `--unit-graph`");
+            return BuildPlan::from_unit_graph(profile, selection);
+        }
+        BuildPlan::from_cargo_output(profile, selection)
+    }
+
+    /// Loads a `BuildPlan` recorded from `cargo build --build-plan` or
+    /// `--unit-graph`. A `path` of `-` reads from stdin.
+    pub fn from_file(path: &Utf8Path) -> anyhow::Result<Self> {
+        let data = if path == "-" {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            data
+        } else {
+            std::fs::read(path)?
+        };
+
+        let build_dir = build_dir()?;
+        let value: serde_json::Value = serde_json::from_slice(data.as_ref())?;
+        if value.get("units").is_some() {
+            let graph: UnitGraph = serde_json::from_slice(data.as_ref())?;
+            return graph.into_build_plan(&build_dir);
+        }
+        let mut plan: BuildPlan = serde_json::from_slice(data.as_ref())?;
+        plan.normalize(&build_dir, cli::cargo_layout());
+        Ok(plan)
+    }
+
+    /// Returns cargo's profile directories used by this plan, e.g. `target/debug`.
+    ///
+    /// These are the parents of the `-L dependency=<dir>/deps` search paths.
+    pub fn profile_dirs(&self) -> BTreeSet<Utf8PathBuf> {
+        self.invocations
+            .iter()
+            .flat_map(|inv| inv.args.iter())
+            .filter_map(|arg| arg.strip_prefix("dependency="))
+            .filter_map(|dir| Utf8Path::new(dir).parent())
+            .map(Utf8Path::to_path_buf)
+            .collect()
+    }
+
+    /// Moves every path under cargo's target directory to `build_dir`, so
+    /// that `target/debug/deps` becomes `<build_dir>/deps`, or
+    /// `<build_dir>/debug/deps` with `cargo_layout`. See [`PathRewrite`].
+    pub fn normalize(&mut self, build_dir: &Utf8Path, cargo_layout: bool) {
+        let profile_dirs = self.profile_dirs();
+        let triples: BTreeSet<&str> = self
+            .invocations
+            .iter()
+            .flat_map(|inv| inv.arg_values("--target"))
+            .collect();
+        let triples: Vec<&str> = triples.into_iter().collect();
+        let rewrite = PathRewrite::profile_dirs(
+            profile_dirs.iter().map(Utf8PathBuf::as_path),
+            &triples,
+            build_dir,
+            cargo_layout,
+        );
+        for inv in &mut self.invocations {
+            inv.rewrite_paths(&rewrite);
+        }
+    }
+
+    /// Merges the plans of several target selections, see `--merge`.
+    ///
+    /// Invocations are deduplicated by [`Invocation::hash_string`] once their
+    /// `deps` index the merged plan. The `links` are left out of the hash and
+    /// combined, cargo only uplifts the units of the selection itself. When
+    /// two units uplift to the same path, the first one keeps it, see
+    /// [`BuildPlan::drop_colliding_links`].
+    pub fn merge(plans: Vec<BuildPlan>) -> BuildPlan {
+        let mut merged = BuildPlan {
+            invocations: Vec::new(),
+            inputs: Vec::new(),
+        };
+        let mut by_hash = BTreeMap::new();
+        for plan in plans {
+            let mut indices = vec![None; plan.invocations.len()];
+            for i in 0..plan.invocations.len() {
+                merged.merge_invocation(&plan, i, &mut indices, &mut by_hash);
+            }
+            for input in plan.inputs {
+                if !merged.inputs.contains(&input) {
+                    merged.inputs.push(input);
+                }
+            }
+        }
+        // selections with different features may uplift different units
+        merged.drop_colliding_links();
+        merged
+    }
+
+    /// Keeps the first of the units uplifted to the same path, e.g. the
+    /// binaries of the same name in several packages, as ninja rejects two
+    /// edges for one output. The others are left in `deps`.
+    fn drop_colliding_links(&mut self) {
+        let mut owners: BTreeMap<Utf8PathBuf, usize> = BTreeMap::new();
+        for index in 0..self.invocations.len() {
+            let links = std::mem::take(&mut self.invocations[index].links);
+            for (link, target) in links {
+                match owners.get(&link) {
+                    Some(&owner) if owner != index => eprintln!(
+                        "warning: `{link}` is uplifted by both `{}` and `{}`, keeping the first",
+                        self.invocations[owner].description(),
+                        self.invocations[index].description(),
+                    ),
+                    _ => {
+                        owners.insert(link.clone(), index);
+                        self.invocations[index].links.insert(link, target);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds invocation `i` of `plan` after its deps, returns its index.
+    fn merge_invocation(
+        &mut self,
+        plan: &BuildPlan,
+        i: usize,
+        indices: &mut [Option<usize>],
+        by_hash: &mut BTreeMap<String, usize>,
+    ) -> usize {
+        if let Some(index) = indices[i] {
+            return index;
+        }
+        let mut inv = plan.invocations[i].clone();
+        inv.deps = inv
+            .deps
+            .iter()
+            .map(|&dep| self.merge_invocation(plan, dep, indices, by_hash))
+            .collect();
+        let links = std::mem::take(&mut inv.links);
+        // a unit selected in one plan may only be a dependency in another
+        let primary = inv.env.remove("CARGO_PRIMARY_PACKAGE");
+        let index = *by_hash.entry(inv.hash_string()).or_insert_with(|| {
+            self.invocations.push(inv);
+            self.invocations.len() - 1
+        });
+        if let Some(primary) = primary {
+            let env = &mut self.invocations[index].env;
+            env.insert("CARGO_PRIMARY_PACKAGE".to_string(), primary);
+        }
+        self.invocations[index].links.extend(links);
+        indices[i] = Some(index);
+        index
+    }
+
+    /// Makes libraries emit their metadata along with the rlib, and the units
+    /// which do not link, see [`Invocation::requires_upstream_objects`], read
+    /// the `.rmeta` of their dependencies like cargo does.
+    ///
+    /// The rmeta has its own edge, see [`Invocation::pipelined_metadata`], so
+    /// these units start while their dependencies are still in codegen.
+    pub fn emit_metadata(&mut self) {
+        let mut rmetas = BTreeSet::new();
+        for inv in &mut self.invocations {
+            let Some(rlib) = inv.outputs.iter().find(|o| o.extension() == Some("rlib")) else {
+                continue;
+            };
+            let rmeta = rlib.with_extension("rmeta");
+            if !inv.outputs.contains(&rmeta) {
+                inv.outputs.push(rmeta.clone());
+            }
+            for arg in &mut inv.args {
+                if let Some(emit) = arg.strip_prefix("--emit=") {
+                    if !emit.split(',').any(|kind| kind == "metadata") {
+                        *arg = format!("--emit={emit},metadata");
+                    }
+                }
+            }
+            rmetas.insert(rmeta);
+        }
+
+        for inv in &mut self.invocations {
+            if inv.requires_upstream_objects() {
+                continue;
+            }
+            for i in 1..inv.args.len() {
+                if inv.args[i - 1] != "--extern" {
+                    continue;
+                }
+                if let Some((name, path)) = inv.args[i].split_once('=') {
+                    let rmeta = Utf8Path::new(path).with_extension("rmeta");
+                    if rmetas.contains(&rmeta) {
+                        inv.args[i] = format!("{name}={rmeta}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the files that invalidate this plan when changed: every
+    /// manifest involved, `Cargo.lock` and cargo's config files.
+    pub fn regenerate_inputs(&self) -> Vec<Utf8PathBuf> {
+        let lockfile = METADATA.workspace_root.join("Cargo.lock");
+        self.inputs
+            .iter()
+            .cloned()
+            .chain(std::iter::once(lockfile))
+            .chain(cargo_config_files())
+            .filter(|p| p.exists())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
     pub fn to_ninja<Filter: Fn(&&Invocation) -> bool>(
         &self,
-        include_custom_build: bool,
+        build_dir: &Utf8Path,
         filter: Filter,
     ) -> FileBuilder {
         let include_builds: Vec<&Invocation> = self.invocations.iter().filter(filter).collect();
         let mut deps: BTreeSet<usize> = BTreeSet::new();
         for invocation in &include_builds {
-            collect_deps_recursively(invocation, self, &mut deps, include_custom_build);
+            collect_deps_recursively(invocation, self, &mut deps);
         }
 
+        let file =
+            self.invocations
+                .iter()
+                .enumerate()
+                .fold(FileBuilder::new(), |builder, (i, inv)| {
+                    if !include_builds.contains(&inv) && !deps.contains(&i) {
+                        return builder;
+                    }
+                    let mut build_scripts: Vec<&Invocation> = Vec::new();
+                    // see `emit_metadata`
+                    let metadata_only = !inv.requires_upstream_objects();
+                    let deps: Vec<Utf8PathBuf> =
+                        inv.deps.iter().fold(Vec::new(), |mut all_outputs, i| {
+                            let dep = &self.invocations[*i];
+                            let rmeta = dep
+                                .outputs()
+                                .into_iter()
+                                .filter(|o| o.extension() == Some("rmeta"));
+                            if metadata_only && rmeta.clone().next().is_some() {
+                                all_outputs.extend(rmeta);
+                                return all_outputs;
+                            }
+                            let mut outputs = dep.outputs();
+                            if dep.is_run_custom_build() {
+                                build_scripts.push(dep);
+                            }
+                            all_outputs.append(&mut outputs);
+                            let mut links: Vec<Utf8PathBuf> =
+                                dep.links().into_iter().map(|(link, _)| link).collect();
+                            all_outputs.append(&mut links);
+                            all_outputs
+                        });
+                    let mut upstream: BTreeSet<usize> = BTreeSet::new();
+                    if !inv.is_run_custom_build() && inv.requires_upstream_objects() {
+                        self.build_scripts_to_link(inv, &mut upstream);
+                    }
+                    let upstream_build_scripts: Vec<&Invocation> = upstream
+                        .into_iter()
+                        .map(|i| &self.invocations[i])
+                        .filter(|dep| !build_scripts.contains(dep))
+                        .collect();
+                    let codegen_deps: Vec<Utf8PathBuf> = inv
+                        .deps
+                        .iter()
+                        .map(|i| &self.invocations[*i])
+                        .filter_map(|dep| Some((dep.pipelined_metadata()?, dep.outputs())))
+                        .flat_map(|(rmeta, outputs)| {
+                            outputs.into_iter().filter(move |o| *o != rmeta)
+                        })
+                        .collect();
+                    builder.merge(&inv.ninja_build(
+                        i,
+                        deps,
+                        codegen_deps,
+                        build_scripts,
+                        upstream_build_scripts,
+                    ))
+                });
+
+        // `ninja doctest` runs the doctests of the selected libraries
+        let doctestable: Vec<(usize, &Invocation)> = self
+            .invocations
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| {
+                include_builds.contains(i)
+                    && i.compile_mode == CompileMode::Build
+                    && i.doctestable()
+            })
+            .collect();
+        let file = doctestable.iter().fold(file, |builder, (i, lib)| {
+            // doctests may use dev-dependencies, which only the test build has
+            let externs = self
+                .invocations
+                .iter()
+                .find(|t| {
+                    t.compile_mode == CompileMode::Test
+                        && t.package_name == lib.package_name
+                        && t.package_version == lib.package_version
+                        && t.target_kind == lib.target_kind
+                })
+                .unwrap_or(lib)
+                .linked_externs();
+            let mut to_link: BTreeSet<usize> = BTreeSet::new();
+            self.build_scripts_to_link(lib, &mut to_link);
+            let (build_scripts, upstream_build_scripts): (Vec<&Invocation>, Vec<&Invocation>) =
+                to_link
+                    .into_iter()
+                    .map(|i| &self.invocations[i])
+                    .partition(|dep| {
+                        dep.package_name == lib.package_name
+                            && dep.package_version == lib.package_version
+                    });
+            builder.merge(&lib.ninja_doctest(*i, externs, build_scripts, upstream_build_scripts))
+        });
+        let stamps: Vec<Utf8PathBuf> = doctestable
+            .iter()
+            .filter_map(|(_, lib)| lib.doctest_stamp().ok())
+            .collect();
+        let file = match stamps.is_empty() {
+            true => file,
+            false => file.output(
+                "doctest",
+                stamps
+                    .iter()
+                    .fold(BuildBuilder::new("phony"), |build, s| build.explicit(s)),
+            ),
+        };
+
+        // `ninja test` runs the selected test binaries
+        let tests: Vec<(usize, &Invocation)> = self
+            .invocations
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| include_builds.contains(i) && i.test_binary().is_some())
+            .collect();
+        let file = tests
+            .iter()
+            .fold(file, |builder, (i, test)| match cli::test_cases() {
+                true => builder.merge(&test.ninja_test_cases(*i)),
+                false => builder.merge(&test.ninja_test(*i)),
+            });
+        // the test cases are only known once the binaries are listed, they
+        // run in a nested ninja over `tests.ninja`
+        let fragments: Vec<Utf8PathBuf> = tests
+            .iter()
+            .filter_map(|(_, t)| t.test_cases_fragment())
+            .collect();
+        let run =
+            |builder: FileBuilder, name: String, stamps: Vec<Utf8PathBuf>| match cli::test_cases()
+                && !stamps.is_empty()
+            {
+                true => builder.merge(&ninja_run_test_cases(
+                    name,
+                    &build_dir.join(TESTS_NINJA),
+                    &fragments,
+                    stamps,
+                )),
+                false => builder.output(
+                    name,
+                    stamps
+                        .iter()
+                        .fold(BuildBuilder::new("phony"), |build, s| build.explicit(s)),
+                ),
+            };
+        let stamps: Vec<Utf8PathBuf> = tests.iter().filter_map(|(_, t)| t.test_stamp()).collect();
+        let file = match stamps.is_empty() {
+            true => file,
+            false => run(file, "test".to_string(), stamps),
+        };
+        // `ninja test-shard-K` runs the K-th of `--test-shards` buckets
+        let file = (0..cli::test_shards().unwrap_or(0)).fold(file, |builder, shard| {
+            let stamps = tests.iter().filter_map(|(_, t)| match cli::test_cases() {
+                true => t.test_shard_stamp(shard),
+                false => t.test_stamp().filter(|_| {
+                    test_shard(&t.test_shard_key(), cli::test_shards().unwrap_or(1)) == shard
+                }),
+            });
+            run(builder, format!("test-shard-{shard}"), stamps.collect())
+        });
+
+        // `ninja run-<bin>` runs the selected binaries and examples
+        let ambiguous = ambiguous_targets(&include_builds, |i| i.run_target(false));
+        let file = self
+            .invocations
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| include_builds.contains(i) && i.run_target(false).is_some())
+            .fold(file, |builder, (i, inv)| {
+                let mut to_link: BTreeSet<usize> = BTreeSet::new();
+                self.build_scripts_to_link(inv, &mut to_link);
+                let build_scripts: Vec<&Invocation> =
+                    to_link.into_iter().map(|i| &self.invocations[i]).collect();
+                let qualified = inv
+                    .run_target(false)
+                    .is_some_and(|t| ambiguous.contains(&t));
+                builder.merge(&inv.ninja_run(i, qualified, build_scripts))
+            });
+
+        // `ninja bench-<name>` runs the selected benches, `bench-compare`
+        // checks their latest results against the baselines
+        let benches: Vec<(usize, &Invocation)> = self
+            .invocations
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| include_builds.contains(i) && i.bench_target(false).is_some())
+            .collect();
+        let ambiguous = ambiguous_targets(&include_builds, |i| i.bench_target(false));
+        let file = benches.iter().fold(file, |builder, (i, bench)| {
+            let qualified = bench
+                .bench_target(false)
+                .is_some_and(|t| ambiguous.contains(&t));
+            builder.merge(&bench.ninja_bench(*i, qualified))
+        });
+        let bench_dirs: Vec<Utf8PathBuf> =
+            benches.iter().filter_map(|(_, b)| b.bench_dir()).collect();
+        let file = match bench_dirs.is_empty() {
+            true => file,
+            false => file.merge(&ninja_bench_compare(&bench_dirs)),
+        };
+
+        // `ninja doc` documents the selected packages like `cargo doc`
+        let docs: Vec<Utf8PathBuf> = include_builds
+            .iter()
+            .filter(|i| i.is_doc())
+            .flat_map(|i| i.outputs())
+            .collect();
+        let file = match docs.is_empty() {
+            true => file,
+            false => file.output(
+                "doc",
+                docs.iter()
+                    .fold(BuildBuilder::new("phony"), |build, o| build.explicit(o)),
+            ),
+        };
+
+        file.merge(&aliases(&include_builds))
+    }
+
+    /// What plain `ninja` builds: the artifacts of the selected packages,
+    /// without those only needed by tests and benches.
+    pub fn default_outputs<Filter: Fn(&&Invocation) -> bool>(
+        &self,
+        filter: Filter,
+    ) -> Vec<Utf8PathBuf> {
+        self.invocations
+            .iter()
+            .filter(filter)
+            .filter(|i| !i.is_run_custom_build() && !i.is_test_build())
+            .filter(|i| !i.is_test() && !i.is_bench())
+            .flat_map(|i| i.products())
+            .collect()
+    }
+
+    /// The rules of the edges in the test case fragments, see `--test-cases`.
+    pub fn test_case_rules(&self) -> FileBuilder {
         self.invocations
             .iter()
             .enumerate()
             .fold(FileBuilder::new(), |builder, (i, inv)| {
-                if !include_builds.contains(&inv) && !deps.contains(&i) {
-                    return builder;
-                }
-                let deps: Vec<Utf8PathBuf> = Vec::new();
-                let mut custom_build_output: Option<BuildScriptOutput> = None;
-
-                let deps: Vec<Utf8PathBuf> = inv.deps.iter().fold(deps, |mut all_outputs, i| {
-                    let dep = &self.invocations[*i];
-                    if !dep.is_run_custom_build() {
-                        let mut outputs = dep.outputs();
-                        all_outputs.append(&mut outputs);
-                        let mut links: Vec<Utf8PathBuf> = self.invocations[*i]
-                            .links()
-                            .into_iter()
-                            .map(|(link, _)| link)
-                            .collect();
-                        all_outputs.append(&mut links);
-                    } else {
-                        custom_build_output = dep
-                            .build_script_output()
-                            .map_err(|e| {
-                                eprintln!("Custom build output error: {e:?}");
-                            })
-                            .ok();
-                    }
-                    all_outputs
-                });
-                builder.merge(&inv.ninja_build(i, deps, custom_build_output))
+                builder.merge(&inv.ninja_test_case_rule(i))
             })
     }
+
+    /// The ninja fragments listing the test cases, see `--test-cases`.
+    pub fn test_cases_fragments(&self) -> Vec<Utf8PathBuf> {
+        match cli::test_cases() {
+            true => self
+                .invocations
+                .iter()
+                .filter(|i| i.is_workspace_build())
+                .filter_map(|i| i.test_cases_fragment())
+                .collect(),
+            false => Vec::new(),
+        }
+    }
+
+    /// Collects the build script runs whose native libraries `invocation`
+    /// links, like cargo's `BuildScripts::to_link`: those of its own package
+    /// and, through linkable dependencies, of every upstream package.
+    fn build_scripts_to_link(&self, invocation: &Invocation, to_link: &mut BTreeSet<usize>) {
+        for &i in &invocation.deps {
+            let dep = &self.invocations[i];
+            if dep.is_run_custom_build() {
+                to_link.insert(i);
+            } else if dep.is_linkable() {
+                self.build_scripts_to_link(dep, to_link);
+            }
+        }
+    }
 }
 
-pub fn with_build_plan<F: FnMut(&BuildPlan) -> Result<(), anyhow::Error>>(
+/// Phony aliases over the products of `invocations`: `<package>`,
+/// `<package>:<target>`, and `bins`, `libs`, `tests`, `examples` and
+/// `benches`.
+///
+/// A package named like another phony target, e.g. `test` or `bins`, only
+/// gets its `<package>:<target>` aliases.
+fn aliases(invocations: &[&Invocation]) -> FileBuilder {
+    let shards = (0..cli::test_shards().unwrap_or(0)).map(|shard| format!("test-shard-{shard}"));
+    let builtins = ["test", "doctest", "doc", "bench-compare", "force"];
+    let kinds = ["bins", "libs", "tests", "examples", "benches"];
+    let reserved: BTreeSet<String> = builtins
+        .into_iter()
+        .chain(kinds)
+        .map(ToString::to_string)
+        .chain(shards)
+        .chain(
+            invocations
+                .iter()
+                .filter_map(|inv| inv.run_target(false).or(inv.bench_target(false)))
+                // without the `<triple>:` prefix of several `--target`s
+                .filter_map(|target| Some(target.rsplit("$:").next()?.to_string())),
+        )
+        .collect();
+    let mut warned = BTreeSet::new();
+    let mut aliases: BTreeMap<String, BTreeSet<Utf8PathBuf>> = BTreeMap::new();
+    for inv in invocations {
+        if inv.is_run_custom_build() || inv.is_custom_build() {
+            continue;
+        }
+        let products = inv.products();
+        // `<triple>:<alias>` builds the alias for one of several `--target`s
+        let triple = inv.target_triple().filter(|_| cli::targets().len() > 1);
+        let mut add = |alias: String| {
+            if let Some(triple) = triple {
+                let prefixed = aliases.entry(format!("{triple}$:{alias}")).or_default();
+                prefixed.extend(products.clone());
+            }
+            aliases.entry(alias).or_default().extend(products.clone())
+        };
+        match reserved.contains(&inv.package_name) {
+            true if warned.insert(inv.package_name.clone()) => eprintln!(
+                "warning: package `{0}` has no `{0}` alias, which is a built-in target, \
+                 use `{0}:<target>`",
+                inv.package_name
+            ),
+            true => {}
+            false => add(inv.package_name.clone()),
+        }
+        // the unit tests of a lib or bin are not the target itself
+        let is_target = !inv.is_test_build() || inv.is_test() || inv.is_bench();
+        if let Some(target_name) = inv.target_name().ok().filter(|_| is_target) {
+            // `:` separates the outputs of a build statement
+            add(format!("{}$:{target_name}", inv.package_name));
+        }
+        let kind = if inv.is_test() {
+            Some("tests")
+        } else if inv.is_bench() {
+            Some("benches")
+        } else if inv.is_example() {
+            Some("examples")
+        } else if inv.is_test_build() {
+            None
+        } else if inv.is_bin() {
+            Some("bins")
+        } else if inv.is_lib() {
+            Some("libs")
+        } else {
+            None
+        };
+        if let Some(kind) = kind {
+            add(kind.to_string());
+        }
+    }
+    aliases
+        .into_iter()
+        .fold(FileBuilder::new(), |builder, (alias, products)| {
+            let build = products
+                .iter()
+                .fold(BuildBuilder::new("phony"), |build, p| build.explicit(p));
+            builder.output(alias, build)
+        })
+}
+
+/// The phony targets named by `target` which invocations of several packages
+/// share, e.g. the `run-<bin>` of binaries of the same name. These are
+/// qualified by the package instead.
+fn ambiguous_targets(
+    invocations: &[&Invocation],
+    target: impl Fn(&Invocation) -> Option<String>,
+) -> BTreeSet<String> {
+    let mut packages: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+    for inv in invocations {
+        if let Some(target) = target(inv) {
+            packages
+                .entry(target)
+                .or_default()
+                .insert(inv.package_name());
+        }
+    }
+    packages
+        .into_iter()
+        .filter(|(_, packages)| packages.len() > 1)
+        .map(|(target, _)| target)
+        .collect()
+}
+
+/// FNV-1a, for hashes which have to stay the same across builds. The output
+/// of `DefaultHasher` may change between Rust releases.
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The [`StableHasher`] hash of `bytes`.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// The bucket of `--test-shards` running the test identified by `key`, by
+/// [`stable_hash`] so the shards don't reshuffle.
+pub fn test_shard(key: &str, shards: usize) -> usize {
+    (stable_hash(key.as_bytes()) % shards.max(1) as u64) as usize
+}
+
+/// The plan of one of the `--profiles`, or the only plan without them.
+pub struct ProfilePlan {
+    pub profile: Option<String>,
+    /// Where the outputs of the profile go, see [`profile_build_dir`].
+    pub build_dir: Utf8PathBuf,
+    pub plan: BuildPlan,
+}
+
+impl ProfilePlan {
+    fn new(profile: Option<&str>) -> anyhow::Result<Self> {
+        let selections = cli::merge();
+        let mut plan = if let Some(path) = cli::plan() {
+            BuildPlan::from_file(&path)?
+        } else if selections.is_empty() {
+            BuildPlan::from_cargo(profile, None)?
+        } else {
+            let plans = selections
+                .iter()
+                .map(|selection| BuildPlan::from_cargo(profile, Some(selection)))
+                .collect::<anyhow::Result<_>>()?;
+            BuildPlan::merge(plans)
+        };
+        plan.drop_colliding_links();
+        plan.emit_metadata();
+        Ok(ProfilePlan {
+            profile: profile.map(ToString::to_string),
+            build_dir: profile_build_dir(profile)?,
+            plan,
+        })
+    }
+}
+
+pub fn with_build_plans<F: FnMut(&[ProfilePlan]) -> Result<(), anyhow::Error>>(
     mut f: F,
 ) -> Result<(), anyhow::Error> {
-    static BUILD_PLAN: OnceLock<BuildPlan> = OnceLock::new();
-    let plan = BuildPlan::from_cargo_output()?;
-    let plan = BUILD_PLAN.get_or_init(|| plan);
-    f(plan)
-}
-
-fn collect_deps_recursively(
-    invocation: &Invocation,
-    plan: &BuildPlan,
-    deps: &mut BTreeSet<usize>,
-    include_custom_build: bool,
-) {
+    static BUILD_PLANS: OnceLock<Vec<ProfilePlan>> = OnceLock::new();
+    // the plan can only be read once from stdin
+    if let Some(plans) = BUILD_PLANS.get() {
+        return f(plans);
+    }
+    let profiles = cli::profiles();
+    let plans = match profiles.is_empty() {
+        true => vec![ProfilePlan::new(None)?],
+        false => profiles
+            .iter()
+            .map(|profile| ProfilePlan::new(Some(profile)))
+            .collect::<anyhow::Result<_>>()?,
+    };
+    let plans = BUILD_PLANS.get_or_init(|| plans);
+    f(plans)
+}
+
+fn collect_deps_recursively(invocation: &Invocation, plan: &BuildPlan, deps: &mut BTreeSet<usize>) {
     for i in invocation.deps.clone() {
         let d = plan.invocations.get(i).unwrap();
-        if !include_custom_build && (d.is_run_custom_build() || d.is_custom_build()) {
-            continue;
-        }
         deps.insert(i);
-        collect_deps_recursively(d, plan, deps, include_custom_build)
+        collect_deps_recursively(d, plan, deps)
+    }
+}
+
+/// Returns the config files cargo would read, from the current directory up
+/// to the root, then `$CARGO_HOME`.
+///
+/// See <https://doc.rust-lang.org/cargo/reference/config.html#hierarchical-structure>.
+pub(crate) fn cargo_config_files() -> Vec<Utf8PathBuf> {
+    let cwd = std::env::current_dir()
+        .ok()
+        .and_then(|p| Utf8PathBuf::from_path_buf(p).ok());
+    let cargo_home = std::env::var("CARGO_HOME")
+        .ok()
+        .map(Utf8PathBuf::from)
+        .or(std::env::var("HOME")
+            .ok()
+            .map(|home| Utf8PathBuf::from(home).join(".cargo")));
+    let dirs = cwd
+        .iter()
+        .flat_map(|cwd| cwd.ancestors().map(|dir| dir.join(".cargo")))
+        .chain(cargo_home);
+    dirs.flat_map(|dir| [dir.join("config.toml"), dir.join("config")])
+        .collect()
+}
+
+/// `check` for a metadata-only graph, see `--check`, `doc` for `--doc`,
+/// otherwise `build`.
+fn cargo_subcommand() -> &'static str {
+    if cli::check() {
+        "check"
+    } else if cli::doc() {
+        "doc"
+    } else {
+        "build"
     }
 }
 
+/// Appends `--profile <profile>` and the target `selection`, e.g. `--tests`,
+/// to `cargo_args`.
+fn plan_args<'a>(
+    cargo_args: &[&'a str],
+    profile: Option<&'a str>,
+    selection: Option<&'a str>,
+) -> Vec<&'a str> {
+    let profile = profile
+        .into_iter()
+        .flat_map(|profile| ["--profile", profile]);
+    cargo_args
+        .iter()
+        .copied()
+        .chain(profile)
+        .chain(selection)
+        .collect()
+}
+
+/// Whether cargo still has the unstable `--build-plan`, which newer cargo
+/// removed. Asked once from `cargo <subcommand> --help`, as the error cargo
+/// gives for it can't be told apart from other failures of the plan.
+fn cargo_has_build_plan() -> bool {
+    static BUILD_PLAN: OnceLock<bool> = OnceLock::new();
+    *BUILD_PLAN.get_or_init(|| {
+        std::process::Command::new("cargo")
+            .args([cargo_subcommand(), "--help"])
+            .output()
+            .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("--build-plan"))
+    })
+}
+
+fn cargo_command(cargo_args: &[&str]) -> std::process::Command {
+    let mut cmd = std::process::Command::new("cargo");
+    if let Ok(dir) = std::env::current_dir() {
+        cmd.current_dir(dir);
+    }
+    args_for_cargo(cargo_args).into_iter().for_each(|arg| {
+        cmd.arg(arg);
+    });
+    cmd.envs(std::env::vars());
+    cmd
+}
+
 pub fn build_dir() -> Result<Utf8PathBuf, anyhow::Error> {
     let build_dir = cli::build_dir()?;
     let build_dir = std::env::current_dir()?.join(build_dir);
@@ -622,44 +1677,228 @@ pub fn build_dir() -> Result<Utf8PathBuf, anyhow::Error> {
     Ok(build_dir)
 }
 
-fn run_build_script(inv: &Invocation) -> Result<(), anyhow::Error> {
-    let out_dir = inv.out_dir()?;
-    let dir = out_dir
-        .parent()
-        .ok_or(anyhow::format_err!("failed to resolve out_dir parent"))?;
-    std::fs::create_dir_all(dir)?;
-    let file = dir.join("build.ninja");
-    if !file.exists() {
-        with_build_plan(|plan| {
-            for i in &plan.invocations {
-                if let Ok(out_dir) = i.out_dir() {
-                    std::fs::create_dir_all(out_dir)?;
-                }
-            }
-            let ninja: File = plan
-                .to_ninja(true, |i| i == &inv)
-                .build()
-                .map_err(|e| anyhow::format_err!("failed to build ninja file: {e:?}"))?;
-            let file = std::fs::File::create(file.clone())?;
-            write_ninja_file(&ninja, file)?;
-            Ok(())
-        })?;
+/// `<build_dir>/<profile>` with `--profiles`, otherwise the build dir.
+pub fn profile_build_dir(profile: Option<&str>) -> anyhow::Result<Utf8PathBuf> {
+    let build_dir = build_dir()?;
+    let Some(profile) = profile else {
+        return Ok(build_dir);
+    };
+    let dir = build_dir.join(profile);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// A private target dir for cargo, removed on drop.
+pub(crate) struct ScratchDir(Utf8PathBuf);
+
+impl ScratchDir {
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let dir = std::env::temp_dir().join(format!("cargo-ninja-{}-{nanos}", std::process::id()));
+        let dir = Utf8PathBuf::from_path_buf(dir)
+            .map_err(|e| anyhow::format_err!("{:?} is not a utf8 path", e))?;
+        std::fs::create_dir_all(&dir)?;
+        Ok(ScratchDir(dir))
+    }
+
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_moves_profile_dirs() {
+        let json = r#"{
+            "invocations": [{
+                "package_name": "foo",
+                "package_version": "0.1.0",
+                "target_kind": ["bin"],
+                "compile_mode": "build",
+                "deps": [],
+                "outputs": ["/ws/target/debug/deps/foo-1234"],
+                "links": {"/ws/target/debug/foo": "/ws/target/debug/deps/foo-1234"},
+                "program": "rustc",
+                "args": ["--out-dir", "/ws/target/debug/deps", "-L", "dependency=/ws/target/debug/deps"],
+                "env": {"CARGO_MANIFEST_DIR": "/ws"},
+                "cwd": "/ws"
+            }],
+            "inputs": ["/ws/Cargo.toml"]
+        }"#;
+        let mut plan: BuildPlan = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            plan.profile_dirs(),
+            BTreeSet::from([Utf8PathBuf::from("/ws/target/debug")])
+        );
+
+        plan.normalize(Utf8Path::new("/build"), false);
+        let inv = &plan.invocations[0];
+        assert_eq!(inv.outputs, vec![Utf8PathBuf::from("/build/deps/foo-1234")]);
+        assert_eq!(
+            inv.links.get(Utf8Path::new("/build/foo")),
+            Some(&Utf8PathBuf::from("/build/deps/foo-1234"))
+        );
+        assert_eq!(inv.args[3], "dependency=/build/deps");
+        assert_eq!(inv.cwd, Some(Utf8PathBuf::from("/ws")));
+
+        // e.g. the saved `build-plan.json` loaded again with `--plan`
+        let normalized = serde_json::to_string(&plan).unwrap();
+        plan.normalize(Utf8Path::new("/build"), false);
+        assert_eq!(serde_json::to_string(&plan).unwrap(), normalized);
     }
 
-    use std::io::{self, Write};
-    use std::process::Command;
+    #[test]
+    fn merge_dedups_and_reindexes() {
+        let inv = |name: &str, kind: &str, deps: &[usize], linked: bool| Invocation {
+            package_name: name.to_string(),
+            package_version: "0.1.0".to_string(),
+            target_kind: serde_json::from_str(&format!("[\"{kind}\"]")).unwrap(),
+            compile_mode: CompileMode::Build,
+            deps: deps.to_vec(),
+            outputs: vec![Utf8PathBuf::from(format!("/build/deps/{name}"))],
+            links: match linked {
+                true => BTreeMap::from([(
+                    Utf8PathBuf::from(format!("/build/{name}")),
+                    Utf8PathBuf::from(format!("/build/deps/{name}")),
+                )]),
+                false => BTreeMap::new(),
+            },
+            program: "rustc".to_string(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            cwd: None,
+        };
+        let libs = BuildPlan {
+            invocations: vec![inv("dep", "lib", &[], false), inv("foo", "lib", &[0], true)],
+            inputs: vec![Utf8PathBuf::from("/ws/Cargo.toml")],
+        };
+        let tests = BuildPlan {
+            invocations: vec![
+                inv("it", "test", &[2], false),
+                inv("dep", "lib", &[], false),
+                inv("foo", "lib", &[1], false),
+            ],
+            inputs: vec![Utf8PathBuf::from("/ws/Cargo.toml")],
+        };
 
-    let output = Command::new("ninja")
-        .arg("-f")
-        .arg(&file)
-        .output()
-        .expect("failed to execute process");
+        let plan = BuildPlan::merge(vec![libs, tests]);
+        let names: Vec<&str> = plan.invocations.iter().map(|i| i.package_name()).collect();
+        assert_eq!(names, ["dep", "foo", "it"]);
+        assert_eq!(plan.invocations[1].deps, [0]);
+        assert_eq!(plan.invocations[1].links.len(), 1);
+        assert_eq!(plan.invocations[2].deps, [1]);
+        assert_eq!(plan.inputs.len(), 1);
 
-    if !output.status.success() {
-        eprintln!("Cmd failed: ninja -f {}", file);
+        // another unit uplifted to the same `/build/foo`
+        let mut other = inv("foo", "lib", &[], true);
+        other.args.push("--cfg=feature=\"x\"".to_string());
+        let featured = BuildPlan {
+            invocations: vec![other],
+            inputs: Vec::new(),
+        };
+        let plan = BuildPlan::merge(vec![plan, featured]);
+        assert_eq!(plan.invocations.len(), 4);
+        assert!(plan.invocations[3].links.is_empty());
+
+        // the same unit, selected in one plan and only a dependency in the other
+        let mut primary = inv("dep", "lib", &[], false);
+        primary
+            .env
+            .insert("CARGO_PRIMARY_PACKAGE".to_string(), "1".to_string());
+        let selected = BuildPlan {
+            invocations: vec![primary],
+            inputs: Vec::new(),
+        };
+        let dependency = BuildPlan {
+            invocations: vec![
+                inv("dep", "lib", &[], false),
+                inv("foo", "bin", &[0], false),
+            ],
+            inputs: Vec::new(),
+        };
+        let plan = BuildPlan::merge(vec![dependency, selected]);
+        assert_eq!(plan.invocations.len(), 2);
+        assert_eq!(plan.invocations[1].deps, [0]);
+        assert_eq!(
+            plan.invocations[0].env.get("CARGO_PRIMARY_PACKAGE"),
+            Some(&"1".to_string())
+        );
+    }
+
+    #[test]
+    fn docs_read_rmeta_externs() {
+        let inv = |kind: &str, mode: CompileMode, outputs: &[&str], args: &[&str]| Invocation {
+            package_name: "foo".to_string(),
+            package_version: "0.1.0".to_string(),
+            target_kind: serde_json::from_str(&format!("[\"{kind}\"]")).unwrap(),
+            compile_mode: mode,
+            deps: vec![0],
+            outputs: outputs.iter().map(Utf8PathBuf::from).collect(),
+            links: BTreeMap::new(),
+            program: "rustc".to_string(),
+            args: args.iter().map(ToString::to_string).collect(),
+            env: BTreeMap::new(),
+            cwd: None,
+        };
+        let externs = ["--extern", "dep=/build/deps/libdep.rlib"];
+        let doc = CompileMode::Doc {
+            deps: false,
+            json: false,
+        };
+        let mut plan = BuildPlan {
+            invocations: vec![
+                inv(
+                    "lib",
+                    CompileMode::Build,
+                    &["/build/deps/libdep.rlib"],
+                    &["--emit=link"],
+                ),
+                inv("bin", CompileMode::Build, &["/build/deps/foo"], &externs),
+                inv("bin", doc, &["/build/doc/foo/index.html"], &externs),
+            ],
+            inputs: Vec::new(),
+        };
+        plan.emit_metadata();
+        let [dep, bin, doc] = &plan.invocations[..] else {
+            unreachable!()
+        };
+        assert_eq!(dep.args, ["--emit=link,metadata"]);
+        assert_eq!(bin.args[1], "dep=/build/deps/libdep.rlib");
+        assert_eq!(doc.args[1], "dep=/build/deps/libdep.rmeta");
     }
-    io::stdout().write_all(&output.stdout).unwrap();
-    io::stderr().write_all(&output.stderr).unwrap();
 
-    Ok(())
+    #[test]
+    fn shared_bin_names_run_qualified() {
+        let bin = |package: &str, name: &str| Invocation {
+            package_name: package.to_string(),
+            package_version: "0.1.0".to_string(),
+            target_kind: serde_json::from_str("[\"bin\"]").unwrap(),
+            compile_mode: CompileMode::Build,
+            deps: Vec::new(),
+            outputs: vec![Utf8PathBuf::from(format!("/build/deps/{package}-{name}"))],
+            links: BTreeMap::new(),
+            program: "rustc".to_string(),
+            args: vec!["--crate-name".to_string(), name.to_string()],
+            env: BTreeMap::new(),
+            cwd: None,
+        };
+        let (app, cli, tool) = (
+            bin("app", "server"),
+            bin("cli", "server"),
+            bin("cli", "tool"),
+        );
+        let ambiguous = ambiguous_targets(&[&app, &cli, &tool], |i| i.run_target(false));
+        assert_eq!(ambiguous, BTreeSet::from(["run-server".to_string()]));
+        assert_eq!(app.run_target(true).unwrap(), "run-app$:server");
+        assert_eq!(tool.run_target(false).unwrap(), "run-tool");
+    }
 }
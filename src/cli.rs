@@ -24,15 +24,46 @@ fn cli() -> clap::Command {
         return cmd();
     }
 }
-pub fn args_for_cargo() -> Vec<String> {
+/// Options only understood by cargo-ninja, along with whether they take a
+/// value. These are never forwarded to cargo.
+const NINJA_ARGS: &[(&str, bool)] = &[
+    ("--unit-graph", false),
+    ("--plan", true),
+    ("--check", false),
+    ("--doc", false),
+    ("--cargo-layout", false),
+    ("--profiles", true),
+    ("--merge", true),
+    ("--test-cases", false),
+    ("--test-shards", true),
+    ("--bench-threshold", true),
+];
+
+/// Target selections `--merge` accepts, as cargo flags.
+const MERGE_SELECTIONS: [&str; 6] = [
+    "--lib",
+    "--bins",
+    "--tests",
+    "--benches",
+    "--examples",
+    "--all-targets",
+];
+
+pub fn args_for_cargo(cargo_args: &[&str]) -> Vec<String> {
     let skip = if from_cargo() { 2 } else { 1 };
+    let mut skip_value = false;
     std::env::args().skip(skip).fold(
-        vec![
-            "-Zunstable-options".to_string(),
-            "build".to_string(),
-            "--build-plan".to_string(),
-        ],
+        cargo_args.iter().map(ToString::to_string).collect(),
         |mut acc, arg| {
+            if std::mem::take(&mut skip_value) {
+                return acc;
+            }
+            if let Some((_, takes_value)) = NINJA_ARGS.iter().find(|(name, _)| {
+                arg == *name || arg.strip_prefix(name).is_some_and(|v| v.starts_with('='))
+            }) {
+                skip_value = *takes_value && !arg.contains('=');
+                return acc;
+            }
             if !build_dir()
                 .ok()
                 .map_or(false, |dir| Utf8PathBuf::from(arg.clone()) == dir)
@@ -71,6 +102,25 @@ fn cmd() -> clap::Command {
         )
         .arg(arg!(-Z <FLAG> "Unstable (nightly-only) flags to Cargo, see 'cargo -Z help' for details)")
             .action(ArgAction::Append))
+        .next_help_heading("Plan Options")
+        .arg(arg!(--"unit-graph"      "Generate from `cargo build --unit-graph` instead of `--build-plan`"))
+        .arg(arg!(--plan <FILE>       "Load a recorded build plan or unit graph instead of running cargo, `-` for stdin")
+            .value_parser(clap::value_parser!(Utf8PathBuf)))
+        .arg(arg!(--check             "Generate a metadata-only graph from `cargo check` instead of `cargo build`")
+            .conflicts_with("doc"))
+        .arg(arg!(--doc               "Generate the documentation graph from `cargo doc` instead of `cargo build`"))
+        .arg(arg!(--"cargo-layout"    "Keep cargo's `<triple>/<profile>/` output layout under BUILD_DIR"))
+        .arg(arg!(--merge <SELECTIONS> "Merge the plans of comma separated target selections, e.g. `lib,bins,tests,benches,examples`")
+            .value_delimiter(',')
+            .value_parser(MERGE_SELECTIONS.map(|flag| &flag[2..]))
+            .conflicts_with_all(["plan", "lib", "bins", "bin", "examples", "example", "tests", "test", "benches", "bench", "all-targets"]))
+        .next_help_heading("Test Options")
+        .arg(arg!(--"test-cases"      "Run every test case as its own edge, listed from the test binaries. The `test` targets run them from a nested `tests.ninja`"))
+        .arg(arg!(--"test-shards" <N>  "Split the tests, or test cases with `--test-cases`, into N `test-shard-K` targets")
+            .value_parser(clap::value_parser!(u64).range(1..)))
+        .arg(arg!(--"bench-threshold" <PERCENT> "Slowdown over the baseline `bench-compare` reports as a regression")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("5"))
         .next_help_heading("Package Selection")
         .arg(arg!(-p --package <SPEC>  "Package to build (see `cargo help pkgid`)")
             .num_args(0..=1)
@@ -101,8 +151,11 @@ fn cmd() -> clap::Command {
         .next_help_heading("Compilation Options")
         .arg(arg!(-r --release                 "Build artifacts in release mode, with optimizations"))
         .arg(arg!(--profile <"PROFILE-NAME">  "Build artifacts with the specified profile"))
-        .arg(arg!(--target <TRIPLE>       "Build for the target triple")
-            .num_args(0..=1))
+        .arg(arg!(--profiles <PROFILES>   "Comma separated profiles to generate side by side, each under BUILD_DIR/<PROFILE>")
+            .value_delimiter(',')
+            .conflicts_with_all(["release", "profile", "plan"]))
+        .arg(arg!(--target <TRIPLE>       "Build for the target triple, may be given several times")
+            .action(ArgAction::Append))
         .arg(arg!(--timings <FMTS>        "Timing output formats (unstable) (comma separated): html, json")
             .num_args(0..=1).require_equals(true))
         .next_help_heading("Manifest Options")
@@ -121,3 +174,227 @@ pub fn build_dir() -> anyhow::Result<Utf8PathBuf> {
             .ok_or(anyhow::format_err!("BUILD_DIR None"))
     })
 }
+
+pub fn unit_graph() -> bool {
+    with_matches(|matches| Ok(matches.get_flag("unit-graph"))).unwrap_or(false)
+}
+
+pub fn plan() -> Option<Utf8PathBuf> {
+    with_matches(|matches| Ok(matches.get_one::<Utf8PathBuf>("plan").cloned()))
+        .ok()
+        .flatten()
+}
+
+pub fn check() -> bool {
+    with_matches(|matches| Ok(matches.get_flag("check"))).unwrap_or(false)
+}
+
+pub fn doc() -> bool {
+    with_matches(|matches| Ok(matches.get_flag("doc"))).unwrap_or(false)
+}
+
+pub fn cargo_layout() -> bool {
+    with_matches(|matches| Ok(matches.get_flag("cargo-layout"))).unwrap_or(false)
+}
+
+pub fn profiles() -> Vec<String> {
+    with_matches(|matches| {
+        Ok(matches
+            .get_many::<String>("profiles")
+            .map(|profiles| profiles.cloned().collect())
+            .unwrap_or_default())
+    })
+    .unwrap_or_default()
+}
+
+/// The cargo flags of the `--merge` selections.
+pub fn merge() -> Vec<&'static str> {
+    with_matches(|matches| {
+        Ok(matches
+            .get_many::<String>("merge")
+            .into_iter()
+            .flatten()
+            .filter_map(|name| MERGE_SELECTIONS.into_iter().find(|flag| flag[2..] == *name))
+            .collect())
+    })
+    .unwrap_or_default()
+}
+
+pub fn targets() -> Vec<String> {
+    with_matches(|matches| {
+        Ok(matches
+            .get_many::<String>("target")
+            .map(|targets| targets.cloned().collect())
+            .unwrap_or_default())
+    })
+    .unwrap_or_default()
+}
+
+pub fn test_cases() -> bool {
+    with_matches(|matches| Ok(matches.get_flag("test-cases"))).unwrap_or(false)
+}
+
+pub fn test_shards() -> Option<usize> {
+    with_matches(|matches| Ok(matches.get_one::<u64>("test-shards").map(|n| *n as usize)))
+        .ok()
+        .flatten()
+}
+
+pub fn bench_threshold() -> f64 {
+    with_matches(|matches| Ok(matches.get_one::<f64>("bench-threshold").copied()))
+        .ok()
+        .flatten()
+        .unwrap_or(5.0)
+}
+
+/// Internal commands invoked by the generated `build.ninja` as
+/// `cargo-ninja --tool <COMMAND>`.
+fn tool_cmd() -> clap::Command {
+    let command = || {
+        arg!(<COMMAND> ... "The command to run")
+            .last(true)
+            .allow_hyphen_values(true)
+    };
+    clap::Command::new("--tool")
+        .subcommand_required(true)
+        .subcommand(
+            clap::Command::new("build-script")
+                .about("Run a build script and record its output")
+                .arg(arg!(--package <NAME>  "Package owning the build script"))
+                .arg(
+                    arg!(--output <FILE>    "Where to write the build script output")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(
+                    arg!(--depfile <FILE>   "Where to write the files watched by the build script")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(
+                    arg!(--"links-metadata" <"LINKS=FILE"> "Output of the build script of a dependency with `links`")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--"env-snapshot" <FILE> "Where to record the `rerun-if-env-changed` variables")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(command()),
+        )
+        .subcommand(
+            clap::Command::new("rustc")
+                .about("Run rustc with the directives of build script outputs applied")
+                .arg(arg!(--package <NAME>  "Package of the compiled target"))
+                .arg(arg!(--"link-lib"      "Pass `rustc-link-lib` directives"))
+                .arg(
+                    arg!(--"link-arg-target" <TARGET> "A `rustc-link-arg*` variant applying to the target")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--"build-script-output" <FILE> "Output of a build script the target depends on")
+                        .value_parser(clap::value_parser!(Utf8PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--"upstream-build-script-output" <FILE> "Output of a build script of an upstream package whose native libraries are linked")
+                        .value_parser(clap::value_parser!(Utf8PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--pipeline <STATE> "Finish once rustc wrote the metadata, leaving the compilation running, see `--wait`")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(arg!(--wait            "Wait for the compilation left running by `--pipeline` and report its result"))
+                .arg(arg!(--supervise       "Run the compilation left running by `--pipeline`").hide(true))
+                .arg(command()),
+        )
+        .subcommand(
+            clap::Command::new("test")
+                .about("Run a test binary, recording its results and whether it passed")
+                .arg(
+                    arg!(--output <FILE>    "Where to write the JSON events of the test run")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(
+                    arg!(--stamp <FILE>     "Touched when all tests passed")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(command()),
+        )
+        .subcommand(
+            clap::Command::new("list-tests")
+                .about("List the tests of a test binary into a ninja fragment with an edge per test")
+                .arg(
+                    arg!(--output <FILE>    "Where to write the ninja fragment")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(arg!(--rule <RULE>     "The rule running a single test, given `$case` and `$json`"))
+                .arg(
+                    arg!(--"cases-dir" <DIR> "Where the edges put the results and stamps of each test")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(
+                    arg!(--stamp <FILE>     "Phony depending on the stamps of every test")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(
+                    arg!(--shards <N>       "Also add a phony per `--test-shards` bucket, next to the stamp")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(arg!(--"shard-key" <KEY> "Identifies the test binary when assigning the buckets"))
+                .arg(command()),
+        )
+        .subcommand(
+            clap::Command::new("run")
+                .about("Run a binary with the dylib search path of `cargo run`")
+                .arg(
+                    arg!(--"library-path" <DIR> "A directory to search for dylibs")
+                        .value_parser(clap::value_parser!(Utf8PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--"target-dir" <DIR> "Only native directories of build scripts within it are searched")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(
+                    arg!(--"build-script-output" <FILE> "Output of a build script whose native libraries are linked")
+                        .value_parser(clap::value_parser!(Utf8PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(command()),
+        )
+        .subcommand(
+            clap::Command::new("bench")
+                .about("Run a bench binary, saving its results and a baseline")
+                .arg(
+                    arg!(--dir <DIR>        "Where to save the results")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                )
+                .arg(command()),
+        )
+        .subcommand(
+            clap::Command::new("bench-compare")
+                .about("Compare the latest bench results with their baselines")
+                .arg(
+                    arg!(--threshold <PERCENT> "Slowdown reported as a regression")
+                        .value_parser(clap::value_parser!(f64)),
+                )
+                .arg(
+                    arg!(<DIR> ...          "The directories `bench` saved the results in")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("env-check")
+                .about("Update a snapshot of `rerun-if-env-changed` variables when their values changed")
+                .arg(
+                    arg!(--snapshot <FILE>  "The snapshot written by `build-script`")
+                        .value_parser(clap::value_parser!(Utf8PathBuf)),
+                ),
+        )
+}
+
+/// Returns the parsed arguments when invoked as `cargo-ninja --tool`.
+pub fn tool_matches() -> Option<ArgMatches> {
+    let mut args = std::env::args().skip(1).peekable();
+    // `--tool` stands in for the binary name
+    (args.peek().map(String::as_str) == Some("--tool")).then(|| tool_cmd().get_matches_from(args))
+}
@@ -18,9 +18,10 @@
 use anyhow::bail;
 use cargo_util::paths;
 use cargo_util_schemas::manifest::RustVersion;
-use ninja_files_data::CommandBuilder;
-use snailquote::escape;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str::{self, FromStr};
 
 use crate::build_plan::Invocation;
@@ -100,7 +101,9 @@ impl LinkArgTarget {
             LinkArgTarget::All => true,
             LinkArgTarget::Cdylib => target.is_cdylib(),
             LinkArgTarget::Bin => target.is_bin(),
-            LinkArgTarget::SingleBin(name) => target.is_bin() && target.package_name() == name,
+            LinkArgTarget::SingleBin(name) => {
+                target.is_bin() && target.target_name().is_ok_and(|n| n == *name)
+            }
             LinkArgTarget::Test => target.is_test(),
             LinkArgTarget::Bench => target.is_bench(),
             LinkArgTarget::Example => target.is_exe_example(),
@@ -108,6 +111,82 @@ impl LinkArgTarget {
     }
 }
 
+impl fmt::Display for LinkArgTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkArgTarget::All => "all".fmt(f),
+            LinkArgTarget::Cdylib => "cdylib".fmt(f),
+            LinkArgTarget::Bin => "bins".fmt(f),
+            LinkArgTarget::SingleBin(name) => write!(f, "bin={name}"),
+            LinkArgTarget::Test => "tests".fmt(f),
+            LinkArgTarget::Bench => "benches".fmt(f),
+            LinkArgTarget::Example => "examples".fmt(f),
+        }
+    }
+}
+
+impl FromStr for LinkArgTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "all" => LinkArgTarget::All,
+            "cdylib" => LinkArgTarget::Cdylib,
+            "bins" => LinkArgTarget::Bin,
+            "tests" => LinkArgTarget::Test,
+            "benches" => LinkArgTarget::Bench,
+            "examples" => LinkArgTarget::Example,
+            _ => match s.strip_prefix("bin=") {
+                Some(name) => LinkArgTarget::SingleBin(name.to_string()),
+                None => bail!("unknown link arg target `{s}`"),
+            },
+        })
+    }
+}
+
+/// The parts of an [`Invocation`] deciding which build script instructions
+/// apply to it.
+///
+/// Build scripts run at build time, so this is handed to the `rustc` tool on
+/// its command line rather than applied while generating `build.ninja`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CustomFlagsTarget {
+    pub package_name: String,
+    /// Whether `rustc-link-lib` applies, i.e. this is a library.
+    pub pass_l_flag: bool,
+    /// The `rustc-link-arg*` variants applying to this target.
+    pub link_arg_targets: Vec<LinkArgTarget>,
+}
+
+impl From<&Invocation> for CustomFlagsTarget {
+    fn from(target: &Invocation) -> Self {
+        // rustdoc doesn't link
+        if target.is_rustdoc() {
+            return CustomFlagsTarget {
+                package_name: target.package_name().to_string(),
+                ..Default::default()
+            };
+        }
+        let link_arg_targets = [
+            LinkArgTarget::All,
+            LinkArgTarget::Cdylib,
+            LinkArgTarget::Bin,
+            LinkArgTarget::SingleBin(target.target_name().unwrap_or_default()),
+            LinkArgTarget::Test,
+            LinkArgTarget::Bench,
+            LinkArgTarget::Example,
+        ]
+        .into_iter()
+        .filter(|lt| lt.applies_to(target))
+        .collect();
+        CustomFlagsTarget {
+            package_name: target.package_name().to_string(),
+            pass_l_flag: target.is_lib(),
+            link_arg_targets,
+        }
+    }
+}
+
 impl BuildScriptOutput {
     /// Like [`BuildOutput::parse`] but from a file path.
     pub fn parse_file(
@@ -505,78 +584,248 @@ impl BuildDeps {
                 .unwrap_or_default(),
         }
     }
+
+    /// The current values of the `rerun-if-env-changed` variables.
+    pub fn env_snapshot(&self) -> EnvSnapshot {
+        env_snapshot(self.rerun_if_env_changed.iter().cloned())
+    }
+
+    /// Writes a gcc-style depfile listing the files watched by the build
+    /// script, so ninja reruns it when one of them changes.
+    ///
+    /// `rerun-if-changed` paths are relative to `pkg_root`, and directories
+    /// stand for every file below them. The directories are listed as well,
+    /// their mtime changes when a file is added or removed after this run.
+    /// Without any `rerun-if-changed`, cargo reruns the script whenever a
+    /// file of the package changes, so the whole package directory is watched.
+    pub fn write_depfile(&self, depfile: &Path, pkg_root: &Path) -> anyhow::Result<()> {
+        let mut files = Vec::new();
+        if self.rerun_if_changed.is_empty() {
+            package_files(pkg_root, &self.build_script_output, false, &mut files)?;
+        } else {
+            for path in &self.rerun_if_changed {
+                let path = pkg_root.join(path);
+                if path.is_dir() {
+                    files.push(path.clone());
+                    package_files(&path, &self.build_script_output, true, &mut files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        let escape = |p: &Path| p.to_string_lossy().replace(' ', "\\ ").replace('#', "\\#");
+        let mut contents = format!("{}:", escape(&self.build_script_output));
+        for file in &files {
+            contents.push_str(" \\\n  ");
+            contents.push_str(&escape(file));
+        }
+        contents.push('\n');
+        paths::write(depfile, contents)
+    }
+}
+
+/// Values of environment variables a build script reruns on, `None` when unset.
+pub type EnvSnapshot = BTreeMap<String, Option<String>>;
+
+/// Reads the current values of `names` from the environment.
+pub fn env_snapshot(names: impl IntoIterator<Item = String>) -> EnvSnapshot {
+    names
+        .into_iter()
+        .map(|name| {
+            let value = std::env::var(&name).ok();
+            (name, value)
+        })
+        .collect()
+}
+
+/// Collects the files below `dir` the way cargo looks for changes in a
+/// package, skipping hidden entries, `target`, nested packages and the
+/// directory holding `build_script_output`. With `dirs`, the directories
+/// below `dir` are collected too.
+fn package_files(
+    dir: &Path,
+    build_script_output: &Path,
+    dirs: bool,
+    files: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            if name == "target"
+                || path.join("Cargo.toml").exists()
+                || build_script_output.starts_with(&path)
+            {
+                continue;
+            }
+            if dirs {
+                files.push(path.clone());
+            }
+            package_files(&path, build_script_output, dirs, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
 }
 
 /// Adds extra rustc flags and environment variables collected from the output
 /// of a build-script to the command to execute, include custom environment
 /// variables and `cfg`.
-pub fn add_custom_flags(
-    cmd: CommandBuilder,
-    output: Option<&BuildScriptOutput>,
-    package_name: &str,
-    target: &Invocation,
-) -> CommandBuilder {
-    if output.is_none() {
-        return cmd;
+pub fn add_custom_flags(cmd: &mut Command, output: &BuildScriptOutput, target: &CustomFlagsTarget) {
+    for cfg in &output.cfgs {
+        cmd.arg("--cfg").arg(cfg);
     }
-    let output = output.unwrap();
 
-    let cmd = output.cfgs.iter().fold(cmd, |cmd, cfg| {
-        cmd.arg("--cfg").arg(escape(cfg.as_str()).into_owned())
-    });
+    for (i, cfg) in output.check_cfgs.iter().enumerate() {
+        if i == 0 {
+            cmd.arg("-Zunstable-options");
+        }
+        cmd.arg("--check-cfg").arg(cfg);
+    }
 
-    let cmd = output
-        .check_cfgs
-        .iter()
-        .enumerate()
-        .fold(cmd, |mut cmd, (i, cfg)| {
-            if i == 0 {
-                cmd = cmd.arg("-Zunstable-options");
-            }
-            cmd.arg("--check-cfg")
-                .arg(escape(cfg.as_str()).into_owned())
-        });
+    for (name, value) in &output.env {
+        cmd.env(name, value);
+    }
 
-    let cmd = output
-        .env
-        .iter()
-        .fold(cmd, |cmd, (name, value)| cmd.env(name, value));
-
-    let mut cmd = output.library_paths.iter().fold(cmd, |cmd, path| {
-        cmd.arg("-L").arg(path.to_string_lossy().into_owned())
-    });
-
-    let pass_l_flag = target.is_lib();
-    if pass_l_flag {
-        cmd = output
-            .library_links
-            .iter()
-            .fold(cmd, |cmd, name| cmd.arg("-l").arg(name.as_str()));
+    for path in &output.library_paths {
+        cmd.arg("-L").arg(path);
     }
 
-    let cmd = output.linker_args.iter().fold(cmd, |cmd, (lt, arg)| {
-        // There was an unintentional change where cdylibs were
-        // allowed to be passed via transitive dependencies. This
-        // clause should have been kept in the `if` block above. For
-        // now, continue allowing it for cdylib only.
+    if target.pass_l_flag {
+        for name in &output.library_links {
+            cmd.arg("-l").arg(name);
+        }
+    }
+
+    for (lt, arg) in &output.linker_args {
+        // Only the build script of the target's own package gets here, for
+        // which cargo applies every variant matching the target. Cargo also
+        // passes `rustc-link-arg-cdylib` of transitive dependencies, which
+        // was unintentional, see `add_upstream_flags`.
         // See https://github.com/rust-lang/cargo/issues/9562
-        if lt.applies_to(target) && *lt == LinkArgTarget::Cdylib {
-            return cmd.arg("-C").arg(format!("link-arg={}", arg));
+        if target.link_arg_targets.contains(lt) {
+            cmd.arg("-C").arg(format!("link-arg={}", arg));
         }
-        cmd
-    });
+    }
+}
 
-    output.metadata.iter().fold(cmd, |cmd, (key, value)| {
-        cmd.env(
-            &format!("DEP_{}_{}", envify(package_name), envify(key)),
-            value,
-        )
-    })
+/// Adds the flags cargo takes from the build scripts of the upstream packages
+/// linked into the target: native library search paths, and
+/// `rustc-link-arg-cdylib` for cdylibs.
+pub fn add_upstream_flags(
+    cmd: &mut Command,
+    output: &BuildScriptOutput,
+    target: &CustomFlagsTarget,
+) {
+    for path in &output.library_paths {
+        cmd.arg("-L").arg(path);
+    }
+
+    if target.link_arg_targets.contains(&LinkArgTarget::Cdylib) {
+        for (lt, arg) in &output.linker_args {
+            if *lt == LinkArgTarget::Cdylib {
+                cmd.arg("-C").arg(format!("link-arg={}", arg));
+            }
+        }
+    }
+}
+
+/// The variable the dynamic linker searches, like cargo's `dylib_path_envvar`.
+pub fn dylib_path_envvar() -> &'static str {
+    if cfg!(windows) {
+        "PATH"
+    } else if cfg!(target_os = "macos") {
+        "DYLD_FALLBACK_LIBRARY_PATH"
+    } else {
+        "LD_LIBRARY_PATH"
+    }
+}
+
+/// The `rustc-link-search` directories within `target_dir`, which cargo adds
+/// to the dylib search path when running a target. Those outside could
+/// shadow system libraries.
+pub fn runtime_library_paths(output: &BuildScriptOutput, target_dir: &Path) -> Vec<PathBuf> {
+    output
+        .library_paths
+        .iter()
+        .map(|path| {
+            let value = path.to_string_lossy();
+            match value.split_once('=') {
+                Some((_kind, path)) => PathBuf::from(path),
+                None => path.clone(),
+            }
+        })
+        .filter(|path| path.starts_with(target_dir))
+        .collect()
 }
 
-fn envify(s: &str) -> String {
+/// Passes the metadata of a build script of a package with `links` to the
+/// build script of a direct dependent, as `DEP_<LINKS>_<KEY>`.
+pub fn add_links_metadata(cmd: &mut Command, links: &str, output: &BuildScriptOutput) {
+    for (key, value) in &output.metadata {
+        cmd.env(format!("DEP_{}_{}", envify(links), envify(key)), value);
+    }
+}
+
+pub(crate) fn envify(s: &str) -> String {
     s.chars()
         .flat_map(|c| c.to_uppercase())
         .map(|c| if c == '-' { '_' } else { c })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_plan::ScratchDir;
+
+    #[test]
+    fn depfile_lists_rerun_if_changed_dirs() {
+        let scratch = ScratchDir::new().unwrap();
+        let root = scratch.path().as_std_path();
+        std::fs::create_dir_all(root.join("assets/nested")).unwrap();
+        std::fs::write(root.join("assets/a.txt"), "").unwrap();
+        let deps = BuildDeps {
+            build_script_output: root.join("out/output"),
+            rerun_if_changed: vec![PathBuf::from("assets")],
+            rerun_if_env_changed: Vec::new(),
+        };
+        let depfile = root.join("output.d");
+        let listed = || {
+            deps.write_depfile(&depfile, root).unwrap();
+            let contents = std::fs::read_to_string(&depfile).unwrap();
+            let mut paths: Vec<PathBuf> = contents
+                .split_whitespace()
+                .skip(1)
+                .filter(|p| *p != "\\")
+                .map(PathBuf::from)
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        // ninja sees the mtime of the directories change when files are added
+        assert_eq!(
+            listed(),
+            ["assets", "assets/a.txt", "assets/nested"].map(|p| root.join(p))
+        );
+
+        // a file added after the first run is listed once the script reran
+        std::fs::write(root.join("assets/nested/b.txt"), "").unwrap();
+        assert_eq!(
+            listed(),
+            [
+                "assets",
+                "assets/a.txt",
+                "assets/nested",
+                "assets/nested/b.txt"
+            ]
+            .map(|p| root.join(p))
+        );
+    }
+}
@@ -10,20 +10,33 @@ mod build_plan;
 mod cli;
 mod crate_type;
 mod custom_build;
+mod path_rewrite;
+mod profiles;
 mod rustc_config;
+mod tool;
+mod unit_graph;
 
-use build_plan::{build_dir, with_build_plan, Invocation};
-use camino::Utf8PathBuf;
-use custom_build::{add_custom_flags, BuildScriptOutput};
+use build_plan::{build_dir, with_build_plans, BuildPlan, Invocation, ProfilePlan};
+use camino::{Utf8Path, Utf8PathBuf};
+use custom_build::CustomFlagsTarget;
 use ninja_files::format::write_ninja_file;
 use ninja_files_data::{BuildBuilder, CommandBuilder, File, FileBuilder, RuleBuilder};
 use snailquote::escape;
+use std::borrow::Cow;
 use std::collections::BTreeSet;
+use std::io::Write;
 
 const BUILD_NINJA: &str = "build.ninja";
+const BUILD_PLAN_JSON: &str = "build-plan.json";
+pub const TESTS_NINJA: &str = "tests.ninja";
 const CONFIGURE_RULE: &str = "configure";
 const LINK_RULE_ID: &str = "link";
 const ENSURE_DIR_ALL_RULE_ID: &str = "ensure_dir_all";
+const ENV_CHECK_RULE_ID: &str = "env_check";
+const BENCH_COMPARE_RULE_ID: &str = "bench_compare";
+const TEST_CASES_RULE_ID: &str = "test_cases";
+/// Always out of date, for edges which have to run on every build.
+const FORCE: &str = "force";
 
 fn link_rule() -> RuleBuilder {
     let command = if cfg!(target_family = "windows") {
@@ -56,6 +69,38 @@ fn ensure_dir_all_rule() -> RuleBuilder {
     RuleBuilder::new(command)
 }
 
+fn env_check_rule() -> RuleBuilder {
+    let command = tool_command("env-check").arg("--snapshot").arg("$out");
+    RuleBuilder::new(command)
+        .variable("restat", "1")
+        .variable("description", "check environment of $out")
+}
+
+/// Sets `env` on `command`, quoted for the shell.
+///
+/// Values with control characters, like the `\x1f` separated
+/// `CARGO_ENCODED_RUSTFLAGS`, are single quoted as is, since the shell does not
+/// understand snailquote's `\u{1f}`. Names which the shell cannot assign, like
+/// `CARGO_BIN_EXE_my-bin`, are passed through `env` instead. They sort after
+/// the upper case names, right before the program.
+fn with_env<K, V>(command: CommandBuilder, env: impl IntoIterator<Item = (K, V)>) -> CommandBuilder
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    env.into_iter().fold(command, |cmd, (name, value)| {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        let value = match value.chars().any(|c| c.is_control() && c != '\n') {
+            true => Cow::Owned(format!("'{}'", value.replace('\'', r"'\''"))),
+            false => escape(value),
+        };
+        match name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            true => cmd.env(name, value),
+            false => cmd.env(format!("env {name}"), value),
+        }
+    })
+}
+
 fn ninja_dir(p: &Utf8PathBuf) -> Option<Utf8PathBuf> {
     p.parent().map(|p| p.to_path_buf().join(".ninja_dir"))
 }
@@ -97,11 +142,63 @@ impl Invocation {
         &self,
         indice: usize,
         deps: Vec<Utf8PathBuf>,
-        build_script_output: Option<BuildScriptOutput>,
+        codegen_deps: Vec<Utf8PathBuf>,
+        build_scripts: Vec<&Invocation>,
+        upstream_build_scripts: Vec<&Invocation>,
     ) -> FileBuilder {
         let rule_id = self.rule_id(indice);
-        let mut rule = {
-            let command = CommandBuilder::new(self.program.clone());
+        let metadata = self.pipelined_metadata();
+        let state = metadata
+            .as_ref()
+            .map(|rmeta| rmeta.with_extension("pipeline"));
+        let command = |wait: bool| {
+            let command = if self.is_run_custom_build() {
+                let command = build_scripts
+                    .iter()
+                    .filter_map(|dep| {
+                        dep.manifest_links()
+                            .zip(dep.build_script_output_file().ok())
+                    })
+                    .fold(tool_command("build-script"), |cmd, (links, output)| {
+                        cmd.arg("--links-metadata")
+                            .arg(escape(format!("{links}={output}").as_str()).into_owned())
+                    });
+                command
+                    .arg("--package")
+                    .arg(escape(self.package_name()).into_owned())
+                    .arg("--output")
+                    .arg(self.build_script_output_file().unwrap().as_str())
+                    .arg("--depfile")
+                    .arg(self.dep_info_file().unwrap().as_str())
+                    .arg("--env-snapshot")
+                    .arg(self.env_snapshot_file().unwrap().as_str())
+                    .arg("--")
+                    .arg(self.program.clone())
+            } else if let Some(state) = &state {
+                let command = rustc_tool_command(
+                    &CustomFlagsTarget::from(self),
+                    &build_scripts,
+                    &upstream_build_scripts,
+                )
+                .arg("--pipeline")
+                .arg(state.as_str());
+                match wait {
+                    true => command.arg("--wait"),
+                    false => command,
+                }
+                .arg("--")
+                .arg(self.program.clone())
+            } else if !build_scripts.is_empty() || !upstream_build_scripts.is_empty() {
+                rustc_tool_command(
+                    &CustomFlagsTarget::from(self),
+                    &build_scripts,
+                    &upstream_build_scripts,
+                )
+                .arg("--")
+                .arg(self.program.clone())
+            } else {
+                CommandBuilder::new(self.program.clone())
+            };
             let command = command.cwd(self.cwd());
 
             let command = self.args().iter().fold(command, |cmd, arg| {
@@ -110,28 +207,24 @@ impl Invocation {
                 }
                 cmd.arg(escape(arg.as_str()).into_owned())
             });
-            let command = command.arg("--error-format=human");
-            let command = self.env.iter().fold(command, |cmd, env| {
-                cmd.env(env.0.as_str(), escape(env.1.as_str()))
-            });
-            let command = add_custom_flags(
-                command,
-                build_script_output.as_ref(),
-                self.package_name.as_str(),
-                self,
-            );
-
-            let command = match self.is_run_custom_build() {
-                true => command
-                    .arg(">")
-                    .arg(self.build_script_output_file().unwrap().as_str()),
-                _ => command,
+            let command = match (self.is_run_custom_build(), &state) {
+                (true, _) => command,
+                // the pipeline renders the diagnostics and watches for the rmeta
+                (_, Some(_)) => command.arg("--error-format=json").arg("--json=artifacts"),
+                _ => command.arg("--error-format=human"),
             };
-
-            RuleBuilder::new(command)
+            with_env(command, &self.env)
+        };
+        let mut rule = match self.is_run_custom_build() {
+            true => RuleBuilder::new(command(false)).variable("restat", "1"),
+            _ => RuleBuilder::new(command(false)),
         };
         let build = BuildBuilder::new(rule_id.clone());
         let build = deps.iter().fold(build, |build, d| build.explicit(d));
+        let build = upstream_build_scripts
+            .iter()
+            .filter_map(|dep| dep.build_script_output_file().ok())
+            .fold(build, |build, output| build.implicit(output));
 
         let mut build = build.variable("description", self.description());
         if let Some(depfile) = self.dep_info_file().ok() {
@@ -139,8 +232,46 @@ impl Invocation {
             build = build.variable("depfile", depfile);
         }
 
-        let file = FileBuilder::new().rule(rule_id.clone(), rule);
-        let file = self.outputs().iter().fold(file, |builder, o| {
+        let mut file = FileBuilder::new().rule(rule_id.clone(), rule);
+        if let Some(snapshot) = self
+            .is_run_custom_build()
+            .then(|| self.env_snapshot_file().ok())
+            .flatten()
+        {
+            // the check runs on every build but only touches the snapshot
+            // when a `rerun-if-env-changed` variable changed
+            build = build.implicit(&snapshot);
+            let check = BuildBuilder::new(ENV_CHECK_RULE_ID).implicit(FORCE);
+            let check = match ninja_dir(&snapshot) {
+                Some(p) => check.implicit(p),
+                _ => check,
+            };
+            file = file
+                .rule(ENV_CHECK_RULE_ID, env_check_rule())
+                .output(&snapshot, check)
+                .output(FORCE, BuildBuilder::new("phony"));
+        }
+        // with a pipeline, `build` only outputs the rmeta and the rest waits
+        // for the codegen, after that of the dependencies so the rlib is
+        // ready to link once it is done
+        let (file, outputs) = match &metadata {
+            Some(rmeta) => {
+                let codegen_id = format!("{rule_id}-codegen");
+                let codegen = codegen_deps.iter().fold(
+                    BuildBuilder::new(codegen_id.clone()).explicit(rmeta),
+                    |codegen, dep| codegen.implicit(dep),
+                );
+                let codegen = codegen.variable("description", self.description());
+                let outputs = self.outputs().into_iter().filter(|o| o != rmeta);
+                let file = outputs.fold(
+                    file.rule(codegen_id, RuleBuilder::new(command(true))),
+                    |builder, o| builder.output(o, codegen.clone()),
+                );
+                (file, vec![rmeta.clone()])
+            }
+            None => (file, self.outputs()),
+        };
+        let file = outputs.iter().fold(file, |builder, o| {
             let build = build.clone();
             let build = match ninja_dir(o) {
                 Some(p) => build.implicit(p),
@@ -168,51 +299,529 @@ impl Invocation {
             builder.merge(&f)
         })
     }
+
+    /// Runs the doctests of a library with `rustdoc --test`, then touches
+    /// [`Invocation::doctest_stamp`].
+    pub fn ninja_doctest(
+        &self,
+        indice: usize,
+        externs: Vec<(String, Utf8PathBuf)>,
+        build_scripts: Vec<&Invocation>,
+        upstream_build_scripts: Vec<&Invocation>,
+    ) -> FileBuilder {
+        let (Ok(stamp), Ok(args)) = (self.doctest_stamp(), self.doctest_args(&externs)) else {
+            return FileBuilder::new();
+        };
+        let rule_id = format!("{}-doctest", self.rule_id(indice));
+        let rustdoc = std::env::var("RUSTDOC").unwrap_or("rustdoc".to_string());
+        let command = match build_scripts.is_empty() && upstream_build_scripts.is_empty() {
+            true => CommandBuilder::new(rustdoc),
+            // rustdoc has no `-l`, the library already carries its native libraries
+            false => rustc_tool_command(
+                &CustomFlagsTarget {
+                    package_name: self.package_name.clone(),
+                    ..Default::default()
+                },
+                &build_scripts,
+                &upstream_build_scripts,
+            )
+            .arg("--")
+            .arg(rustdoc),
+        };
+        let command = args
+            .iter()
+            .fold(command.cwd(self.cwd()), |cmd, arg| {
+                cmd.arg(escape(arg.as_str()).into_owned())
+            })
+            .arg("&&")
+            .arg("touch")
+            .arg("$out");
+        let command = with_env(command, &self.env);
+
+        let build = BuildBuilder::new(rule_id.clone()).variable(
+            "description",
+            format!(
+                "doctest target {} for {}@{}",
+                self.target_kind.description(),
+                self.package_name,
+                self.package_version
+            ),
+        );
+        let build = self
+            .outputs()
+            .iter()
+            .chain(externs.iter().map(|(_, path)| path))
+            .fold(build, |build, input| build.explicit(input));
+        let build = build_scripts
+            .iter()
+            .chain(&upstream_build_scripts)
+            .filter_map(|dep| dep.build_script_output_file().ok())
+            .fold(build, |build, output| build.implicit(output));
+        let build = match ninja_dir(&stamp) {
+            Some(p) => build.implicit(p),
+            _ => build,
+        };
+        let file = FileBuilder::new()
+            .rule(rule_id, RuleBuilder::new(command))
+            .output(&stamp, build);
+        match ninja_dir(&stamp) {
+            Some(dir) => file
+                .rule(ENSURE_DIR_ALL_RULE_ID, ensure_dir_all_rule())
+                .output(dir, BuildBuilder::new(ENSURE_DIR_ALL_RULE_ID)),
+            None => file,
+        }
+    }
+
+    /// Runs a test binary with `cargo-ninja --tool test` from the package
+    /// directory, like `cargo test`.
+    pub fn ninja_test(&self, indice: usize) -> FileBuilder {
+        let (Some(binary), Some(output), Some(stamp)) =
+            (self.test_binary(), self.test_output(), self.test_stamp())
+        else {
+            return FileBuilder::new();
+        };
+        let rule_id = format!("{}-run", self.rule_id(indice));
+        let command = self
+            .test_tool_command("test")
+            .arg("--output")
+            .arg(output.as_str())
+            .arg("--stamp")
+            .arg(stamp.as_str())
+            .arg("--")
+            .arg(binary.as_str());
+        let build = BuildBuilder::new(rule_id.clone())
+            .explicit(&binary)
+            .variable("description", self.test_description());
+        FileBuilder::new()
+            .rule(rule_id, RuleBuilder::new(command))
+            .output(&stamp, build.clone())
+            .implicit_output(&output, build)
+    }
+
+    /// Lists the tests of a test binary into [`Invocation::test_cases_fragment`],
+    /// whose edges run a single test each with the rule of
+    /// [`Invocation::ninja_test_case_rule`].
+    ///
+    /// The fragment is included by `tests.ninja`, see [`ninja_run_test_cases`],
+    /// and its test stamps make up [`Invocation::test_stamp`].
+    pub fn ninja_test_cases(&self, indice: usize) -> FileBuilder {
+        let (Some(binary), Some(fragment), Some(stamp)) = (
+            self.test_binary(),
+            self.test_cases_fragment(),
+            self.test_stamp(),
+        ) else {
+            return FileBuilder::new();
+        };
+        let list_rule_id = format!("{}-list", self.rule_id(indice));
+        let list = self
+            .test_tool_command("list-tests")
+            .arg("--output")
+            .arg("$out")
+            .arg("--rule")
+            .arg(self.test_case_rule_id(indice).as_str())
+            .arg("--cases-dir")
+            .arg(binary.with_extension("test.cases").as_str())
+            .arg("--stamp")
+            .arg(stamp.as_str());
+        let list = match cli::test_shards() {
+            Some(shards) => list
+                .arg("--shards")
+                .arg(shards.to_string())
+                .arg("--shard-key")
+                .arg(escape(self.test_shard_key().as_str()).into_owned()),
+            None => list,
+        }
+        .arg("--")
+        .arg(binary.as_str());
+        let build = BuildBuilder::new(list_rule_id.clone())
+            .explicit(&binary)
+            .variable("description", format!("list {}", self.test_description()));
+        FileBuilder::new()
+            .rule(list_rule_id, RuleBuilder::new(list).variable("restat", "1"))
+            .output(&fragment, build)
+    }
+
+    fn test_case_rule_id(&self, indice: usize) -> String {
+        format!("{}-case", self.rule_id(indice))
+    }
+
+    /// The rule of the edges of [`Invocation::ninja_test_cases`], running a
+    /// single test `$case` of the binary.
+    pub fn ninja_test_case_rule(&self, indice: usize) -> FileBuilder {
+        let Some(binary) = self.test_binary() else {
+            return FileBuilder::new();
+        };
+        let case = self
+            .test_tool_command("test")
+            .arg("--output")
+            .arg("$json")
+            .arg("--stamp")
+            .arg("$out")
+            .arg("--")
+            .arg(binary.as_str())
+            .arg("--exact")
+            .arg("$case");
+        FileBuilder::new().rule(
+            self.test_case_rule_id(indice),
+            RuleBuilder::new(case).variable("description", "test $case"),
+        )
+    }
+
+    /// Runs the binary in the `console` pool with the environment of
+    /// `cargo run`, for `ninja run-<bin>`, see [`Invocation::run_target`].
+    pub fn ninja_run(
+        &self,
+        indice: usize,
+        qualified: bool,
+        build_scripts: Vec<&Invocation>,
+    ) -> FileBuilder {
+        let (Some(name), Some(executable)) = (self.run_target(qualified), self.executable()) else {
+            return FileBuilder::new();
+        };
+        let rule_id = format!("{}-run", self.rule_id(indice));
+        let command = self
+            .outputs()
+            .iter()
+            .filter_map(|o| o.parent())
+            .fold(tool_command("run"), |cmd, dir| {
+                cmd.arg("--library-path").arg(dir.as_str())
+            });
+        let command = match build_dir() {
+            Ok(dir) => command.arg("--target-dir").arg(dir.as_str()),
+            Err(_) => command,
+        };
+        let command = build_scripts
+            .iter()
+            .filter_map(|dep| dep.build_script_output_file().ok())
+            .fold(command, |cmd, output| {
+                cmd.arg("--build-script-output").arg(output.as_str())
+            })
+            .arg("--")
+            .arg(executable.as_str());
+        let command = with_env(command, &self.runtime_env());
+        let rule = RuleBuilder::new(command)
+            .variable("pool", "console")
+            .variable("description", format!("run {}", executable));
+        let build = build_scripts
+            .iter()
+            .filter_map(|dep| dep.build_script_output_file().ok())
+            .fold(
+                BuildBuilder::new(rule_id.clone()).explicit(&executable),
+                |build, output| build.implicit(output),
+            );
+        FileBuilder::new().rule(rule_id, rule).output(name, build)
+    }
+
+    /// Runs the bench binary with `--bench` in the `console` pool, so the
+    /// benches don't compete for the cores.
+    pub fn ninja_bench(&self, indice: usize, qualified: bool) -> FileBuilder {
+        let (Some(name), Some(binary), Some(dir)) = (
+            self.bench_target(qualified),
+            self.outputs().into_iter().next(),
+            self.bench_dir(),
+        ) else {
+            return FileBuilder::new();
+        };
+        let rule_id = format!("{}-bench", self.rule_id(indice));
+        let command = self
+            .test_tool_command("bench")
+            .arg("--dir")
+            .arg(dir.as_str())
+            .arg("--")
+            .arg(binary.as_str())
+            .arg("--bench");
+        let rule = RuleBuilder::new(command)
+            .variable("pool", "console")
+            .variable(
+                "description",
+                format!("bench {}", self.target_name().unwrap_or_default()),
+            );
+        let build = BuildBuilder::new(rule_id.clone()).explicit(&binary);
+        FileBuilder::new()
+            .rule(rule_id, rule)
+            .output(name, build.clone())
+            .implicit_output(dir.join(tool::BENCH_LATEST), build)
+    }
+
+    fn test_description(&self) -> String {
+        format!(
+            "test target {} for {}@{}",
+            self.target_kind.description(),
+            self.package_name,
+            self.package_version
+        )
+    }
+
+    /// Runs `cargo-ninja --tool <tool>` with the environment of the test
+    /// binary, from the package directory.
+    fn test_tool_command(&self, tool: &str) -> CommandBuilder {
+        let command = tool_command(tool).cwd(self.manifest_dir());
+        with_env(command, &self.env)
+    }
+}
+
+/// `ninja <name>` builds `targets` of `tests_ninja`, the test case edges
+/// listed in `fragments`, with a nested ninja. Only these targets depend on
+/// the fragments, so other ninja runs don't build and list the test binaries.
+pub fn ninja_run_test_cases(
+    name: String,
+    tests_ninja: &Utf8Path,
+    fragments: &[Utf8PathBuf],
+    targets: Vec<Utf8PathBuf>,
+) -> FileBuilder {
+    let ninja = std::env::var("NINJA").unwrap_or("ninja".to_string());
+    let command = CommandBuilder::new(ninja)
+        .arg("-f")
+        .arg(tests_ninja.as_str())
+        .arg("$targets");
+    let rule = RuleBuilder::new(command)
+        .variable("pool", "console")
+        .variable("description", "run the test cases");
+    let targets: Vec<&str> = targets.iter().map(|t| t.as_str()).collect();
+    let build = fragments
+        .iter()
+        .fold(BuildBuilder::new(TEST_CASES_RULE_ID), |build, f| {
+            build.explicit(f)
+        })
+        .variable("targets", targets.join(" "));
+    FileBuilder::new()
+        .rule(TEST_CASES_RULE_ID, rule)
+        .output(name, build)
+}
+
+/// `ninja bench-compare` runs the benches, then reports those slower than
+/// their baseline by more than `--bench-threshold`.
+pub fn ninja_bench_compare(bench_dirs: &[Utf8PathBuf]) -> FileBuilder {
+    let command = bench_dirs.iter().fold(
+        tool_command("bench-compare")
+            .arg("--threshold")
+            .arg(cli::bench_threshold().to_string()),
+        |cmd, dir| cmd.arg(dir.as_str()),
+    );
+    let rule = RuleBuilder::new(command)
+        .variable("pool", "console")
+        .variable("description", "compare benches with their baselines");
+    let build = bench_dirs
+        .iter()
+        .fold(BuildBuilder::new(BENCH_COMPARE_RULE_ID), |build, dir| {
+            build.explicit(dir.join(tool::BENCH_LATEST))
+        });
+    FileBuilder::new()
+        .rule(BENCH_COMPARE_RULE_ID, rule)
+        .output("bench-compare", build)
+}
+
+/// Runs rustc through `cargo-ninja --tool rustc`, which applies the outputs
+/// of `build_scripts` and `upstream_build_scripts` to the command after `--`.
+fn rustc_tool_command(
+    target: &CustomFlagsTarget,
+    build_scripts: &[&Invocation],
+    upstream_build_scripts: &[&Invocation],
+) -> CommandBuilder {
+    let command = tool_command("rustc")
+        .arg("--package")
+        .arg(escape(target.package_name.as_str()).into_owned());
+    let command = match target.pass_l_flag {
+        true => command.arg("--link-lib"),
+        false => command,
+    };
+    let command = target.link_arg_targets.iter().fold(command, |cmd, lt| {
+        cmd.arg("--link-arg-target")
+            .arg(escape(lt.to_string().as_str()).into_owned())
+    });
+    let command = build_scripts
+        .iter()
+        .filter_map(|dep| dep.build_script_output_file().ok())
+        .fold(command, |cmd, output| {
+            cmd.arg("--build-script-output").arg(output.as_str())
+        });
+    upstream_build_scripts
+        .iter()
+        .filter_map(|dep| dep.build_script_output_file().ok())
+        .fold(command, |cmd, output| {
+            cmd.arg("--upstream-build-script-output")
+                .arg(output.as_str())
+        })
 }
 
-fn configure() -> anyhow::Result<FileBuilder> {
+/// Runs `cargo-ninja --tool <tool>`, see [`tool`].
+fn tool_command(tool: &str) -> CommandBuilder {
+    let program = std::env::current_exe()
+        .ok()
+        .and_then(|p| Utf8PathBuf::from_path_buf(p).ok())
+        .expect("cargo-ninja should be a utf8 path");
+    CommandBuilder::new(program.to_string())
+        .arg("--tool")
+        .arg(tool)
+}
+
+fn configure(plans: &[ProfilePlan], build_dir: &Utf8Path) -> anyhow::Result<FileBuilder> {
     let program_name = std::env::args()
         .next()
         .ok_or(anyhow::format_err!("failed to find program name"))?;
+    let saved_plan = build_dir.join(BUILD_PLAN_JSON);
     let configure_rule = {
         let mut command = CommandBuilder::new(program_name.clone());
         if let Ok(cwd) = std::env::current_dir() {
             let cwd = Utf8PathBuf::from_path_buf(cwd).ok();
             command = command.cwd(cwd);
         }
+        // stdin is gone when ninja regenerates, read the saved plan instead
+        let mut plan_value = false;
         let command = std::env::args().skip(1).fold(command, |cmd, arg| {
+            let arg = match arg.as_str() {
+                "-" if std::mem::take(&mut plan_value) => saved_plan.to_string(),
+                "--plan=-" => format!("--plan={saved_plan}"),
+                _ => arg,
+            };
+            plan_value = arg == "--plan";
             cmd.arg(escape(arg.as_str()).into_owned())
         });
-        let command = std::env::vars().fold(command, |cmd, env| {
-            cmd.env(env.0.as_str(), escape(env.1.as_str()))
-        });
+        let command = with_env(command, std::env::vars());
         RuleBuilder::new(command).generator(true)
     };
 
-    let configure_build = { BuildBuilder::new(CONFIGURE_RULE) };
+    let inputs = match cli::plan() {
+        Some(path) if path == "-" => vec![saved_plan],
+        Some(path) => vec![path],
+        None => Vec::new(),
+    };
+    let configure_build = plans
+        .iter()
+        .flat_map(|p| p.plan.regenerate_inputs())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .chain(inputs)
+        .fold(BuildBuilder::new(CONFIGURE_RULE), |build, input| {
+            build.implicit(input)
+        });
 
-    let builder = FileBuilder::new()
+    // the `build.ninja` of every profile, see `--profiles`
+    let builder = plans
+        .iter()
+        .filter(|p| p.profile.is_some())
+        .fold(FileBuilder::new(), |builder, p| {
+            builder.implicit_output(p.build_dir.join(BUILD_NINJA), configure_build.clone())
+        })
         .rule(CONFIGURE_RULE, configure_rule)
         .output(BUILD_NINJA, configure_build);
     Ok(builder)
 }
 
 fn main() -> Result<(), anyhow::Error> {
+    if let Some(matches) = cli::tool_matches() {
+        return tool::main(&matches);
+    }
     let build_dir = build_dir()?;
-    with_build_plan(|plan| {
-        for i in &plan.invocations {
-            if let Ok(out_dir) = i.out_dir() {
-                std::fs::create_dir_all(out_dir)?;
+    with_build_plans(|plans| {
+        for ProfilePlan {
+            plan, build_dir, ..
+        } in plans
+        {
+            for i in &plan.invocations {
+                if let Ok(out_dir) = i.out_dir() {
+                    std::fs::create_dir_all(out_dir)?;
+                }
             }
+            std::fs::write(
+                build_dir.join(BUILD_PLAN_JSON),
+                serde_json::to_vec_pretty(plan)?,
+            )?;
         }
-        let ninja: File = configure()?
-            .merge(&plan.to_ninja(false, |i| i.is_workspace_build()))
-            .build()
-            .map_err(|e| anyhow::format_err!("failed to build ninja file: {e:?}"))?;
+        let configure = configure(plans, &build_dir)?;
         let file = std::fs::File::create(build_dir.join(BUILD_NINJA))?;
-        write_ninja_file(&ninja, file)?;
+        let [ProfilePlan {
+            profile: None,
+            plan,
+            ..
+        }] = plans
+        else {
+            return write_profiles(plans, configure, file);
+        };
+        let ninja = configure.merge(&plan.to_ninja(&build_dir, |i| i.is_workspace_build()));
+        let mut file = write_plan(plan, &build_ninja(&ninja)?, &build_dir, file)?;
+        write_default(&mut file, plan)?;
         Ok(())
     })?;
 
     Ok(())
 }
+
+fn build_ninja(builder: &FileBuilder) -> anyhow::Result<File> {
+    builder
+        .build()
+        .map_err(|e| anyhow::format_err!("failed to build ninja file: {e:?}"))
+}
+
+/// Writes `ninja`, and with `--test-cases` the `tests.ninja` next to it,
+/// which has the rules of the test cases and `subninja`s their fragments.
+fn write_plan<W: Write>(
+    plan: &BuildPlan,
+    ninja: &File,
+    build_dir: &Utf8Path,
+    file: W,
+) -> anyhow::Result<W> {
+    let fragments = plan.test_cases_fragments();
+    if !fragments.is_empty() {
+        let rules = build_ninja(&plan.test_case_rules())?;
+        let tests = std::fs::File::create(build_dir.join(TESTS_NINJA))?;
+        let mut tests = write_ninja_file(&rules, tests)?;
+        // its own `.ninja_log`, apart from the one of `build.ninja`
+        writeln!(tests, "builddir = {}", build_dir.join("tests"))?;
+        for fragment in &fragments {
+            writeln!(tests, "subninja {fragment}")?;
+        }
+    }
+    Ok(write_ninja_file(ninja, file)?)
+}
+
+fn write_default(file: &mut impl Write, plan: &BuildPlan) -> anyhow::Result<()> {
+    let defaults = plan.default_outputs(|i| i.is_workspace_build());
+    if !defaults.is_empty() {
+        write!(file, "default")?;
+        for output in defaults {
+            write!(file, " {output}")?;
+        }
+        writeln!(file)?;
+    }
+    Ok(())
+}
+
+/// Writes the plan of every profile to `<build_dir>/<profile>/build.ninja`
+/// and `subninja`s them from `file`, which gets the rules they share. The
+/// first profile is built by default.
+fn write_profiles(
+    plans: &[ProfilePlan],
+    configure: FileBuilder,
+    file: std::fs::File,
+) -> anyhow::Result<()> {
+    let mut ninjas = plans
+        .iter()
+        .map(|p| {
+            let ninja = build_ninja(&p.plan.to_ninja(&p.build_dir, |i| i.is_workspace_build()))?;
+            Ok(match &p.profile {
+                Some(profile) => profiles::prefix_phonies(ninja, profile),
+                None => ninja,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let shared = profiles::take_shared_rules(&mut ninjas);
+    let mut top = build_ninja(&configure)?;
+    top.rules.extend(shared.rules);
+
+    let mut file = write_ninja_file(&top, file)?;
+    for (p, ninja) in plans.iter().zip(&ninjas) {
+        let subninja = p.build_dir.join(BUILD_NINJA);
+        write_plan(
+            &p.plan,
+            ninja,
+            &p.build_dir,
+            std::fs::File::create(&subninja)?,
+        )?;
+        writeln!(file, "subninja {subninja}")?;
+    }
+    if let Some(p) = plans.first() {
+        write_default(&mut file, &p.plan)?;
+    }
+    Ok(())
+}
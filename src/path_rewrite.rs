@@ -0,0 +1,178 @@
+//! Moves the paths of a build plan from cargo's target directory to the
+//! build directory of the generated `build.ninja`.
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// Maps directories to their new location, matching whole path components
+/// so `target/debug` doesn't rewrite `target/debugger`.
+#[derive(Debug, Default)]
+pub struct PathRewrite {
+    /// Longest source first, so nested dirs win over their parents.
+    dirs: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+}
+
+impl PathRewrite {
+    /// Moves cargo's profile directories, `<target>/<profile>` and
+    /// `<target>/<triple>/<profile>`, into `build_dir`.
+    ///
+    /// With `cargo_layout` they keep their `<triple>/<profile>` part,
+    /// otherwise the profile is dropped like in [`crate::unit_graph`] plans.
+    /// The rest of `<target>` moves to `build_dir` as is. Directories already
+    /// in `build_dir` stay, so a normalized plan is normalized again as is.
+    pub fn profile_dirs<'a>(
+        profile_dirs: impl IntoIterator<Item = &'a Utf8Path>,
+        triples: &[&str],
+        build_dir: &Utf8Path,
+        cargo_layout: bool,
+    ) -> Self {
+        let mut rewrite = PathRewrite::default();
+        for profile_dir in profile_dirs {
+            if profile_dir.starts_with(build_dir) {
+                continue;
+            }
+            let (Some(parent), Some(profile)) = (profile_dir.parent(), profile_dir.file_name())
+            else {
+                continue;
+            };
+            let (target_dir, dest) = match parent.file_name() {
+                Some(triple) if triples.contains(&triple) => {
+                    (parent.parent().unwrap_or(parent), build_dir.join(triple))
+                }
+                _ => (parent, build_dir.to_path_buf()),
+            };
+            let dest = match cargo_layout {
+                true => dest.join(profile),
+                false => dest,
+            };
+            rewrite.add(profile_dir, &dest);
+            if !target_dir.starts_with(build_dir) {
+                rewrite.add(target_dir, build_dir);
+            }
+        }
+        rewrite
+    }
+
+    /// Moves everything under `from` to `to`.
+    pub fn add(&mut self, from: &Utf8Path, to: &Utf8Path) {
+        if from == to || self.dirs.iter().any(|(dir, _)| dir == from) {
+            return;
+        }
+        self.dirs.push((from.to_path_buf(), to.to_path_buf()));
+        self.dirs
+            .sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+    }
+
+    pub fn path(&self, path: &Utf8Path) -> Utf8PathBuf {
+        self.dirs
+            .iter()
+            .find_map(|(from, to)| {
+                let rest = path.strip_prefix(from).ok()?;
+                Some(match rest.as_str() {
+                    "" => to.clone(),
+                    _ => to.join(rest),
+                })
+            })
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// Rewrites the paths in a command line argument or environment value,
+    /// also when embedded like `dependency=PATH`, `name=PATH` or
+    /// `--emit=link,dep-info=PATH`.
+    pub fn arg(&self, arg: &str) -> String {
+        let mut rewritten = String::with_capacity(arg.len());
+        let mut rest = arg;
+        while let Some(i) = rest.find(['=', ',']) {
+            rewritten.push_str(self.path(Utf8Path::new(&rest[..i])).as_str());
+            rewritten.push_str(&rest[i..i + 1]);
+            rest = &rest[i + 1..];
+        }
+        rewritten.push_str(self.path(Utf8Path::new(rest)).as_str());
+        rewritten
+    }
+
+    /// Rewrites an environment value like [`PathRewrite::arg`], and every
+    /// entry of a path list like `LD_LIBRARY_PATH`.
+    pub fn env(&self, value: &str) -> String {
+        let separator = if cfg!(windows) { ";" } else { ":" };
+        value
+            .split(separator)
+            .map(|entry| self.arg(entry))
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_whole_components() {
+        let rewrite = PathRewrite::profile_dirs(
+            [
+                Utf8Path::new("/ws/target/debug"),
+                Utf8Path::new("/ws/target/aarch64-unknown-linux-gnu/debug"),
+            ],
+            &["aarch64-unknown-linux-gnu"],
+            Utf8Path::new("/build"),
+            false,
+        );
+        assert_eq!(
+            rewrite.path(Utf8Path::new("/ws/target/debug/deps/foo")),
+            "/build/deps/foo"
+        );
+        assert_eq!(
+            rewrite.path(Utf8Path::new(
+                "/ws/target/aarch64-unknown-linux-gnu/debug/deps"
+            )),
+            "/build/aarch64-unknown-linux-gnu/deps"
+        );
+        assert_eq!(rewrite.path(Utf8Path::new("/ws/target/tmp")), "/build/tmp");
+        assert_eq!(
+            rewrite.path(Utf8Path::new("/ws/target-debug/foo")),
+            "/ws/target-debug/foo"
+        );
+        assert_eq!(
+            rewrite.arg("--emit=link,dep-info=/ws/target/debug/deps/foo.d"),
+            "--emit=link,dep-info=/build/deps/foo.d"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn rewrites_path_lists() {
+        let rewrite = PathRewrite::profile_dirs(
+            [Utf8Path::new("/ws/target/debug")],
+            &[],
+            Utf8Path::new("/build"),
+            false,
+        );
+        assert_eq!(
+            rewrite.env("/ws/target/debug/deps:/usr/lib:/ws/target/debug/build/foo/out"),
+            "/build/deps:/usr/lib:/build/build/foo/out"
+        );
+    }
+
+    #[test]
+    fn keeps_cargo_layout() {
+        let rewrite = PathRewrite::profile_dirs(
+            [
+                Utf8Path::new("/ws/target/my-profile"),
+                Utf8Path::new("/ws/target/aarch64-unknown-linux-gnu/my-profile"),
+            ],
+            &["aarch64-unknown-linux-gnu"],
+            Utf8Path::new("/build"),
+            true,
+        );
+        assert_eq!(
+            rewrite.arg("dependency=/ws/target/my-profile/deps"),
+            "dependency=/build/my-profile/deps"
+        );
+        assert_eq!(
+            rewrite.path(Utf8Path::new(
+                "/ws/target/aarch64-unknown-linux-gnu/my-profile/foo"
+            )),
+            "/build/aarch64-unknown-linux-gnu/my-profile/foo"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Side by side profiles, see `--profiles`. The plan of every profile goes to
+//! its own `build.ninja`, which the top-level `build.ninja` `subninja`s.
+
+use camino::Utf8Path;
+use ninja_files_data::{Build, BuildOutput, File, FileName, Rule, RuleId};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Prefixes the phony targets of `file`, the outputs which are not paths,
+/// with `<profile>:` so that `test` of `release` becomes `release:test`.
+pub fn prefix_phonies(file: File, profile: &str) -> File {
+    let phonies: BTreeSet<FileName> = file
+        .builds
+        .values()
+        .flat_map(|outputs| outputs.explicits.iter().chain(&outputs.implicits))
+        .filter(|output| !AsRef::<Utf8Path>::as_ref(*output).is_absolute())
+        .cloned()
+        .collect();
+    let rename = |names: &BTreeSet<FileName>| -> BTreeSet<FileName> {
+        names
+            .iter()
+            .map(|name| match phonies.contains(name) {
+                // `:` separates the outputs of a build statement
+                true => FileName::try_create(format!("{profile}$:{}", AsRef::<str>::as_ref(name)))
+                    .unwrap_or_else(|_| name.clone()),
+                false => name.clone(),
+            })
+            .collect()
+    };
+    let builds = file
+        .builds
+        .iter()
+        .map(|(build, outputs)| {
+            let build = Build {
+                explicits: rename(&build.explicits),
+                implicits: rename(&build.implicits),
+                order: rename(&build.order),
+                ..build.clone()
+            };
+            let outputs = BuildOutput {
+                explicits: rename(&outputs.explicits),
+                implicits: rename(&outputs.implicits),
+            };
+            (build, outputs)
+        })
+        .collect();
+    File { builds, ..file }
+}
+
+/// Moves the rules which every file defines the same way to the returned
+/// file, so they are written once in the top-level `build.ninja`.
+pub fn take_shared_rules(files: &mut [File]) -> File {
+    let Some((first, rest)) = files.split_first() else {
+        return File::default();
+    };
+    let rules: BTreeMap<RuleId, Rule> = first
+        .rules
+        .iter()
+        .filter(|(id, rule)| rest.iter().all(|file| file.rules.get(*id) == Some(*rule)))
+        .map(|(id, rule)| (id.clone(), rule.clone()))
+        .collect();
+    for file in files.iter_mut() {
+        file.rules.retain(|id, _| !rules.contains_key(id));
+    }
+    File {
+        rules,
+        ..File::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ninja_files_data::{BuildBuilder, CommandBuilder, FileBuilder, RuleBuilder};
+
+    #[test]
+    fn prefixes_phonies_and_shares_rules() {
+        let file = |opt_level: &str| {
+            FileBuilder::new()
+                .rule("touch", RuleBuilder::new(CommandBuilder::new("touch")))
+                .rule(
+                    "rustc",
+                    RuleBuilder::new(CommandBuilder::new("rustc").arg(opt_level)),
+                )
+                .output("/build/server", BuildBuilder::new("rustc"))
+                .output(
+                    "server",
+                    BuildBuilder::new("phony").explicit("/build/server"),
+                )
+                .output("test", BuildBuilder::new("phony").explicit("server"))
+                .build()
+                .unwrap()
+        };
+        let release = prefix_phonies(file("-Copt-level=3"), "release");
+        let names: BTreeSet<&str> = release
+            .builds
+            .iter()
+            .flat_map(|(build, outputs)| build.explicits.iter().chain(&outputs.explicits))
+            .map(AsRef::as_ref)
+            .collect();
+        assert_eq!(
+            names,
+            BTreeSet::from(["/build/server", "release$:server", "release$:test"])
+        );
+
+        let mut files = [file("-Copt-level=0"), release];
+        let shared = take_shared_rules(&mut files);
+        let ids = |file: &File| -> Vec<String> {
+            file.rules
+                .keys()
+                .map(|id| AsRef::<str>::as_ref(id).to_string())
+                .collect()
+        };
+        assert_eq!(ids(&shared), ["touch"]);
+        assert!(files.iter().all(|file| ids(file) == ["rustc"]));
+    }
+}
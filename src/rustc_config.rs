@@ -90,6 +90,115 @@ never  = never colorize output"))
         .arg(arg!(-v --verbose "Use verbose output"))
 }
 
+/// The arguments cargo passes to `rustdoc`, for documentation, doctests and
+/// example scraping.
+pub fn rustdoc() -> clap::Command {
+    clap::Command::new("rustdoc")
+        .arg(arg!(<INPUT> "source").value_parser(clap::value_parser!(Utf8PathBuf)))
+        .arg(
+            arg!(--cfg <SPEC>
+            "pass a --cfg to rustc")
+            .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"check-cfg" <SPEC>
+            "pass a --check-cfg to rustc")
+            .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(-L --"library-path" <DIR>
+            "directory to add to crate search path")
+            .action(ArgAction::Append),
+        )
+        .arg(arg!(--"crate-type" <TYPE>
+            "Comma separated list of types of crates for the compiler to emit"))
+        .arg(arg!(--"crate-name" <NAME>
+            "specify the name of this crate"))
+        .arg(arg!(--"crate-version" <VERSION>
+            "crate version to print into documentation"))
+        .arg(arg!(--edition <EDITION>
+            "edition to use when compiling rust code (default: 2015)"))
+        .arg(arg!(--emit <"TYPE[,TYPE]">
+            "Comma separated list of types of output for rustdoc to emit"))
+        .arg(arg!(-o --"out-dir" <PATH> "which directory to place the output"))
+        .arg(arg!(--target <TRIPLE> "target triple to document"))
+        .arg(arg!(--"document-private-items" "document private items"))
+        .arg(arg!(--"document-hidden-items" "document items that have doc(hidden)"))
+        .arg(
+            arg!(--"extern-html-root-url" <"NAME=URL">
+            "base URL to use for dependencies")
+            .action(ArgAction::Append),
+        )
+        .arg(arg!(--"extern-html-root-takes-precedence"
+            "give precedence to `--extern-html-root-url`, not `html_root_url`"))
+        .arg(arg!(--"scrape-examples-output-path" <PATH>
+            "collect function call information and output at the given path"))
+        .arg(
+            arg!(--"scrape-examples-target-crate" <CRATE>
+            "collect function call information for functions from the target crate")
+            .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"with-examples" <PATH>
+            "path to function call information (for displaying examples in the documentation)")
+            .action(ArgAction::Append),
+        )
+        .arg(arg!(--test "run code examples as tests"))
+        .arg(
+            arg!(--"test-args" <ARGS>
+            "arguments to pass to the test runner")
+            .action(ArgAction::Append),
+        )
+        .arg(arg!(--"test-run-directory" <PATH>
+            "The working directory in which to run tests"))
+        .arg(arg!(--"persist-doctests" <PATH>
+            "Directory to persist doctest executables into"))
+        .arg(arg!(--"no-run" "Compile doctests without running them"))
+        .arg(arg!(--"test-builder" <PATH>
+            "The rustc-like binary to use as the test builder"))
+        .arg(arg!(--"runtool" <TOOL>
+            "The tool to run tests with when building for a different target than host"))
+        .arg(arg!(-A --allow <LINT>    "Set lint allowed"))
+        .arg(arg!(-W --warn <LINT>     "Set lint warnings"))
+        .arg(arg!(--"force-warn" <LINT> "Set lint force-warn"))
+        .arg(arg!(-D --deny <LINT>     "Set lint denied"))
+        .arg(arg!(-F --forbid <LINT>   "Set lint forbidden"))
+        .arg(arg!(--"cap-lints" <LEVEL>
+            "Set the most restrictive lint level"))
+        .arg(
+            arg!(-C --codegen <"OPT[=VALUE]">
+            "pass a codegen option to rustc")
+            .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--extern <"NAME[=PATH]">
+            "pass an --extern to rustc")
+            .action(ArgAction::Append),
+        )
+        .arg(arg!(--sysroot <PATH>
+            "Override the system root"))
+        .arg(
+            arg!(-Z <FLAG> "unstable / perma-unstable options (only on nightly build)")
+                .action(ArgAction::Append),
+        )
+        .arg(arg!(--"error-format" <FORMAT>
+            "How errors and other messages are produced"))
+        .arg(
+            arg!(--json <CONFIG> "Configure the structure of JSON diagnostics")
+                .action(ArgAction::Append),
+        )
+        .arg(arg!(--color <COLOR>
+            "Configure coloring of output"))
+        .arg(arg!(--"diagnostic-width" <WIDTH>
+            "Provide width of the output for truncated error messages"))
+        .arg(
+            arg!(--"remap-path-prefix" <"FROM=TO">
+            "Remap source names in compiler messages")
+            .action(ArgAction::Append),
+        )
+        .arg(arg!(-v --verbose "use verbose output"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -101,6 +210,14 @@ mod tests {
         assert_eq!(input, Some(&Utf8PathBuf::from("lib.rs")));
     }
 
+    #[test]
+    fn rustdoc_input() {
+        let args = ["rustdoc", "--edition=2021", "--crate-type", "lib", "--crate-name", "my_proj", "src/lib.rs", "-o", "/tmp/proj/build/doc", "-Zunstable-options", "--emit=toolchain-shared-resources,invocation-specific,dep-info=/tmp/proj/build/doc/my_proj-28a7ee0ffca4f68a.d", "-C", "metadata=28a7ee0ffca4f68a", "-L", "dependency=/tmp/proj/build/deps", "--extern", "foo_sys=/tmp/proj/build/deps/libfoo_sys-22a2f09445e39242.rmeta", "--crate-version", "0.1.0", "--error-format=human"];
+        let matches = rustdoc().get_matches_from(args);
+        let input = matches.get_one::<Utf8PathBuf>("INPUT");
+        assert_eq!(input, Some(&Utf8PathBuf::from("src/lib.rs")));
+    }
+
     #[test]
     fn test() {
         let args = ["rustc", "--crate-name", "cargo_ninja", "--edition=2021", "src/main.rs", "--crate-type", "bin", "--emit=dep-info,link", "-C", "embed-bitcode=no", "-C", "debuginfo=2", "-C", "metadata=040056ab44031190", "-C", "extra-filename=-040056ab44031190", "--out-dir", "/home/declan/src/cargo-ninja/builddir/deps", "-C", "incremental=/home/declan/src/cargo-ninja/builddir/incremental", "-L", "dependency=/home/declan/src/cargo-ninja/builddir/deps", "--extern", "anyhow=/home/declan/src/cargo-ninja/builddir/deps/libanyhow-a0fdca5964864e0f.rlib", "--extern", "camino=/home/declan/src/cargo-ninja/builddir/deps/libcamino-a476909115397406.rlib", "--extern", "cargo_util=/home/declan/src/cargo-ninja/builddir/deps/libcargo_util-f63b173d29067151.rlib", "--extern", "cargo_util_schemas=/home/declan/src/cargo-ninja/builddir/deps/libcargo_util_schemas-3a505a01b7568eec.rlib", "--extern", "cargo_metadata=/home/declan/src/cargo-ninja/builddir/deps/libcargo_metadata-9e2c4e2b66a5a93a.rlib", "--extern", "clap=/home/declan/src/cargo-ninja/builddir/deps/libclap-796664f02e83d62c.rlib", "--extern", "ninja_files_data=/home/declan/src/cargo-ninja/builddir/deps/libninja_files_data2-4d3340732c142be6.rlib", "--extern", "ninja_files=/home/declan/src/cargo-ninja/builddir/deps/libninja_files2-f66972fdbb663726.rlib", "--extern", "pathdiff=/home/declan/src/cargo-ninja/builddir/deps/libpathdiff-602708d6b396de84.rlib", "--extern", "serde=/home/declan/src/cargo-ninja/builddir/deps/libserde-b3e3479ed1a980e0.rlib", "--extern", "serde_derive=/home/declan/src/cargo-ninja/builddir/deps/libserde_derive-badbf5fd040a4378.so", "--extern", "serde_json=/home/declan/src/cargo-ninja/builddir/deps/libserde_json-e1fa0a3f8528d24e.rlib", "--extern", "snailquote=/home/declan/src/cargo-ninja/builddir/deps/libsnailquote-8a178f26917bb5a0.rlib", "--error-format=human"];
@@ -0,0 +1,616 @@
+//! Commands run by the generated `build.ninja` at build time.
+//!
+//! Build scripts only run when ninja builds, so everything derived from their
+//! output is applied here instead of while generating `build.ninja`.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::process::{Command, ExitStatus};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::build_plan::{stable_hash, test_shard};
+use crate::custom_build::{
+    add_custom_flags, add_links_metadata, add_upstream_flags, dylib_path_envvar, env_snapshot,
+    runtime_library_paths, BuildDeps, BuildScriptOutput, CustomFlagsTarget, EnvSnapshot,
+    LinkArgTarget,
+};
+
+pub fn main(matches: &ArgMatches) -> anyhow::Result<()> {
+    let status = match matches.subcommand() {
+        Some(("build-script", matches)) => build_script(matches)?,
+        Some(("rustc", matches)) => rustc(matches)?,
+        Some(("test", matches)) => test(matches)?,
+        Some(("list-tests", matches)) => list_tests(matches)?,
+        Some(("run", matches)) => run(matches)?,
+        Some(("bench", matches)) => bench(matches)?,
+        Some(("bench-compare", matches)) => bench_compare(matches)?,
+        Some(("env-check", matches)) => env_check(matches)?,
+        _ => unreachable!("clap should ensure we don't get here"),
+    };
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+/// Whether `$RUSTC`, which built the test binaries, is a nightly toolchain.
+fn nightly() -> bool {
+    let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+    Command::new(rustc)
+        .arg("-vV")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .is_some_and(|version| {
+            version
+                .lines()
+                .filter_map(|line| line.strip_prefix("release: "))
+                .any(|release| release.contains("-nightly") || release.contains("-dev"))
+        })
+}
+
+fn command(matches: &ArgMatches) -> Command {
+    let mut args = matches.get_many::<String>("COMMAND").into_iter().flatten();
+    let mut cmd = Command::new(args.next().expect("COMMAND is required"));
+    cmd.args(args);
+    cmd
+}
+
+fn package(matches: &ArgMatches) -> &str {
+    matches
+        .get_one::<String>("package")
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+fn parse_output(file: &Utf8Path, package: &str) -> anyhow::Result<BuildScriptOutput> {
+    let dir = file
+        .parent()
+        .ok_or(anyhow::format_err!("failed to get output dir"))?;
+    BuildScriptOutput::parse_file(
+        file.as_std_path(),
+        Some(package.to_string()),
+        package,
+        dir.as_std_path(),
+        dir.as_std_path(),
+        true,
+        true,
+        &None,
+    )
+}
+
+/// Runs a build script, then records its stdout in `--output`.
+///
+/// The output is only rewritten when it changed, so with `restat` ninja skips
+/// recompiling the package when the script reran to the same result.
+fn build_script(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let package = package(matches);
+    let file = matches
+        .get_one::<Utf8PathBuf>("output")
+        .ok_or(anyhow::format_err!("--output is required"))?;
+    if let Some(out_dir) = std::env::var_os("OUT_DIR") {
+        std::fs::create_dir_all(out_dir)?;
+    }
+
+    let mut cmd = command(matches);
+    for links_metadata in matches
+        .get_many::<String>("links-metadata")
+        .into_iter()
+        .flatten()
+    {
+        let (links, file) = links_metadata.split_once('=').ok_or(anyhow::format_err!(
+            "expected LINKS=FILE, got `{links_metadata}`"
+        ))?;
+        let output = parse_output(Utf8Path::new(file), package)?;
+        add_links_metadata(&mut cmd, links, &output);
+    }
+    let output = cmd.stderr(std::process::Stdio::inherit()).output()?;
+    if !output.status.success() {
+        eprintln!("failed to run custom build command for `{package}`");
+        std::io::Write::write_all(&mut std::io::stderr(), &output.stdout)?;
+        return Ok(output.status);
+    }
+
+    let tmp = file.with_extension("tmp");
+    std::fs::write(&tmp, &output.stdout)?;
+    let parsed = parse_output(&tmp, package)?;
+    for warning in &parsed.warnings {
+        eprintln!("warning: {package}@build-script: {warning}");
+    }
+    let deps = BuildDeps::new(file.as_std_path(), Some(&parsed));
+
+    // written before the output so the output stays the newer file
+    if let Some(snapshot) = matches.get_one::<Utf8PathBuf>("env-snapshot") {
+        write_env_snapshot(snapshot, &deps.env_snapshot())?;
+    }
+    if std::fs::read(file).is_ok_and(|old| old == output.stdout) {
+        std::fs::remove_file(&tmp)?;
+    } else {
+        std::fs::rename(&tmp, file)?;
+    }
+
+    if let Some(depfile) = matches.get_one::<Utf8PathBuf>("depfile") {
+        let pkg_root = match std::env::var_os("CARGO_MANIFEST_DIR") {
+            Some(dir) => dir.into(),
+            None => std::env::current_dir()?,
+        };
+        deps.write_depfile(depfile.as_std_path(), &pkg_root)?;
+    }
+    Ok(output.status)
+}
+
+/// Runs rustc with the flags and environment variables from the outputs of
+/// the build scripts the target depends on.
+///
+/// With `--pipeline`, the compilation is split over two edges, see
+/// [`Pipeline`].
+fn rustc(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let pipeline = matches
+        .get_one::<Utf8PathBuf>("pipeline")
+        .map(Pipeline::new);
+    let wait = matches.get_flag("wait");
+    match &pipeline {
+        Some(pipeline) if !wait && !matches.get_flag("supervise") => return pipeline.start(),
+        Some(pipeline) if wait => {
+            if let Some(status) = pipeline.wait()? {
+                return Ok(status);
+            }
+        }
+        _ => {}
+    }
+
+    let package = package(matches);
+    let link_arg_targets = matches
+        .get_many::<String>("link-arg-target")
+        .into_iter()
+        .flatten()
+        .map(|t| t.parse::<LinkArgTarget>())
+        .collect::<anyhow::Result<_>>()?;
+    let target = CustomFlagsTarget {
+        package_name: package.to_string(),
+        pass_l_flag: matches.get_flag("link-lib"),
+        link_arg_targets,
+    };
+
+    let mut cmd = command(matches);
+    if pipeline.is_some() && wait {
+        // nothing was left running, e.g. ninja was interrupted: compile
+        // without rewriting the metadata, which dependents already read
+        let args: Vec<String> = cmd
+            .get_args()
+            .filter_map(|arg| arg.to_str())
+            .map(|arg| match arg.strip_prefix("--emit=") {
+                Some(emit) => {
+                    let kinds: Vec<&str> = emit.split(',').filter(|k| *k != "metadata").collect();
+                    format!("--emit={}", kinds.join(","))
+                }
+                None => arg.to_string(),
+            })
+            .collect();
+        cmd = Command::new(cmd.get_program());
+        cmd.args(args);
+    }
+    for file in matches
+        .get_many::<Utf8PathBuf>("build-script-output")
+        .into_iter()
+        .flatten()
+    {
+        let output = parse_output(file, package)?;
+        add_custom_flags(&mut cmd, &output, &target);
+    }
+    for file in matches
+        .get_many::<Utf8PathBuf>("upstream-build-script-output")
+        .into_iter()
+        .flatten()
+    {
+        let output = parse_output(file, package)?;
+        add_upstream_flags(&mut cmd, &output, &target);
+    }
+    match pipeline {
+        Some(pipeline) if wait => {
+            let status = pipeline.supervise(cmd, None::<std::io::Stdout>)?;
+            std::io::stderr().write_all(&std::fs::read(pipeline.file("log"))?)?;
+            Ok(status)
+        }
+        Some(pipeline) => pipeline.supervise(cmd, Some(std::io::stdout())),
+        None => Ok(cmd.status()?),
+    }
+}
+
+/// A rustc compilation split over two ninja edges, like cargo's pipelining.
+///
+/// The first edge outputs the `.rmeta`. It starts the compilation in a
+/// supervisor process and finishes as soon as rustc reports the metadata, so
+/// the dependents which only read it can start while the codegen goes on.
+/// The second edge outputs the rest and waits for the supervisor, then prints
+/// the diagnostics and fails if the compilation did.
+///
+/// The supervisor holds a lock on `<state>.lock` while rustc runs, sends the
+/// rendered diagnostics to `<state>.log` and its exit code to
+/// `<state>.status`.
+struct Pipeline {
+    state: Utf8PathBuf,
+}
+
+impl Pipeline {
+    fn new(state: &Utf8PathBuf) -> Self {
+        Pipeline {
+            state: state.clone(),
+        }
+    }
+
+    fn file(&self, extension: &str) -> Utf8PathBuf {
+        Utf8PathBuf::from(format!("{}.{extension}", self.state))
+    }
+
+    /// Starts the supervisor, the same command with `--supervise`, and waits
+    /// until it reports the metadata or finishes.
+    fn start(&self) -> anyhow::Result<ExitStatus> {
+        let _ = std::fs::remove_file(self.file("status"));
+        let log = std::fs::File::create(self.file("log"))?;
+        let mut args = std::env::args_os().skip(1);
+        // `--tool rustc` first, then the options of `rustc`
+        let tool: Vec<_> = args.by_ref().take(2).collect();
+        let mut supervisor = Command::new(std::env::current_exe()?)
+            .args(tool)
+            .arg("--supervise")
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(log)
+            .spawn()?;
+        let stdout = supervisor.stdout.take().expect("stdout is piped");
+        let mut line = String::new();
+        std::io::BufReader::new(stdout).read_line(&mut line)?;
+        if line.trim_end() == METADATA_READY {
+            // the supervisor goes on without us
+            return Ok(ExitStatus::default());
+        }
+        let status = supervisor.wait()?;
+        std::io::stderr().write_all(&std::fs::read(self.file("log"))?)?;
+        Ok(status)
+    }
+
+    /// Runs `cmd`, rustc with `--json=artifacts`, writing [`METADATA_READY`]
+    /// to `ready` once the metadata is written.
+    fn supervise(
+        &self,
+        mut cmd: Command,
+        mut ready: Option<impl Write>,
+    ) -> anyhow::Result<ExitStatus> {
+        let lock = std::fs::File::create(self.file("lock"))?;
+        lock.lock()?;
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file("log"))?;
+        let mut rustc = cmd
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let stderr = rustc.stderr.take().expect("stderr is piped");
+        for line in std::io::BufReader::new(stderr).lines() {
+            let line = line?;
+            let Ok(message) = serde_json::from_str::<serde_json::Value>(&line) else {
+                writeln!(log, "{line}")?;
+                continue;
+            };
+            if let Some(rendered) = message["rendered"].as_str() {
+                log.write_all(rendered.as_bytes())?;
+            } else if message["emit"] == "metadata" {
+                if let Some(mut ready) = ready.take() {
+                    // the first edge may be gone, e.g. interrupted
+                    let _ = writeln!(ready, "{METADATA_READY}").and_then(|_| ready.flush());
+                }
+            }
+        }
+        let status = rustc.wait()?;
+        let code = status.code().unwrap_or(1);
+        let tmp = self.file("status.tmp");
+        std::fs::write(&tmp, code.to_string())?;
+        std::fs::rename(tmp, self.file("status"))?;
+        Ok(status)
+    }
+
+    /// Waits for the supervisor, then prints its diagnostics and returns the
+    /// status of rustc. `None` when no compilation was left running.
+    fn wait(&self) -> anyhow::Result<Option<ExitStatus>> {
+        let lock = std::fs::File::create(self.file("lock"))?;
+        lock.lock()?;
+        let Ok(code) = std::fs::read_to_string(self.file("status")) else {
+            return Ok(None);
+        };
+        // the next run compiles again unless the first edge reruns
+        std::fs::remove_file(self.file("status"))?;
+        std::io::stderr().write_all(&std::fs::read(self.file("log"))?)?;
+        Ok(Some(exit_status(code.trim().parse().unwrap_or(1))))
+    }
+}
+
+/// What the supervisor of a [`Pipeline`] writes once the metadata is ready.
+const METADATA_READY: &str = "metadata";
+
+fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    return std::os::unix::process::ExitStatusExt::from_raw(code << 8);
+    #[cfg(windows)]
+    return std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+}
+
+/// Runs a libtest binary with JSON output into `--output` and touches
+/// `--stamp` when every test passed.
+///
+/// Only the failures are printed, with the output they captured.
+fn test(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let file = matches
+        .get_one::<Utf8PathBuf>("output")
+        .ok_or(anyhow::format_err!("--output is required"))?;
+    let stamp = matches
+        .get_one::<Utf8PathBuf>("stamp")
+        .ok_or(anyhow::format_err!("--stamp is required"))?;
+    let _ = std::fs::remove_file(stamp);
+
+    // `--format=json` is unstable, a stable libtest only accepts it under
+    // RUSTC_BOOTSTRAP, which the tests then see too. Nightly doesn't need it.
+    let mut cmd = command(matches);
+    if std::env::var_os("RUSTC_BOOTSTRAP").is_none() && !nightly() {
+        cmd.env("RUSTC_BOOTSTRAP", "1");
+    }
+    let output = cmd
+        .args(["-Zunstable-options", "--format=json"])
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    std::fs::write(file, &output.stdout)?;
+    if output.status.success() {
+        std::fs::write(stamp, "")?;
+        return Ok(output.status);
+    }
+
+    let events = output
+        .stdout
+        .split(|b| *b == b'\n')
+        .filter_map(|line| serde_json::from_slice::<serde_json::Value>(line).ok());
+    for event in events {
+        if event["type"] != "test" || event["event"] != "failed" {
+            continue;
+        }
+        let name = event["name"].as_str().unwrap_or_default();
+        eprintln!("---- {name} ----");
+        if let Some(stdout) = event["stdout"].as_str() {
+            eprint!("{stdout}");
+        }
+        eprintln!("test {name} ... FAILED");
+    }
+    Ok(output.status)
+}
+
+/// Escapes `path` for the paths of a ninja build statement.
+fn escape_path(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "$ ")
+        .replace(':', "$:")
+}
+
+/// Lists the tests of a libtest binary into a ninja fragment with an edge per
+/// test, so ninja schedules the tests of all binaries together.
+///
+/// The fragment is only rewritten when the tests changed, it is a `subninja`
+/// of `tests.ninja`.
+fn list_tests(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let required = |name: &str| {
+        matches
+            .get_one::<Utf8PathBuf>(name)
+            .ok_or(anyhow::format_err!("--{name} is required"))
+    };
+    let (file, cases_dir, stamp) = (
+        required("output")?,
+        required("cases-dir")?,
+        required("stamp")?,
+    );
+    let rule = matches
+        .get_one::<String>("rule")
+        .ok_or(anyhow::format_err!("--rule is required"))?;
+    let binary = matches
+        .get_one::<String>("COMMAND")
+        .ok_or(anyhow::format_err!("COMMAND is required"))?;
+
+    let output = command(matches)
+        .args(["--list", "--format", "terse"])
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Ok(output.status);
+    }
+    std::fs::create_dir_all(cases_dir)?;
+    let mut fragment = String::new();
+    let mut stamps = Vec::new();
+    let list = String::from_utf8(output.stdout)?;
+    for case in list.lines().filter_map(|line| line.strip_suffix(": test")) {
+        let file_name: String = case
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect();
+        // `a::b` and `a__b` sanitize to the same name
+        let file_name = format!("{file_name}-{:016x}", stable_hash(case.as_bytes()));
+        let case_stamp = escape_path(cases_dir.join(format!("{file_name}.stamp")).as_str());
+        let json = escape_path(cases_dir.join(format!("{file_name}.json")).as_str());
+        fragment.push_str(&format!(
+            "build {case_stamp} | {json} : {rule} {}\n  case = {}\n  json = {json}\n",
+            escape_path(binary),
+            snailquote::escape(case).replace('$', "$$")
+        ));
+        stamps.push((case, case_stamp));
+    }
+    fragment.push_str(&format!("build {} : phony", escape_path(stamp.as_str())));
+    for (_, case_stamp) in &stamps {
+        fragment.push_str(&format!(" {case_stamp}"));
+    }
+    fragment.push('\n');
+    if let Some(&shards) = matches.get_one::<usize>("shards") {
+        let key = matches
+            .get_one::<String>("shard-key")
+            .map(String::as_str)
+            .unwrap_or(binary);
+        for shard in 0..shards {
+            fragment.push_str(&format!(
+                "build {} : phony",
+                escape_path(stamp.with_extension(format!("shard-{shard}")).as_str())
+            ));
+            for (case, case_stamp) in &stamps {
+                if test_shard(&format!("{key} {case}"), shards) == shard {
+                    fragment.push_str(&format!(" {case_stamp}"));
+                }
+            }
+            fragment.push('\n');
+        }
+    }
+
+    if !std::fs::read(file).is_ok_and(|old| old == fragment.as_bytes()) {
+        std::fs::write(file, fragment)?;
+    }
+    Ok(output.status)
+}
+
+/// Runs a binary with `--library-path` and the native directories of the
+/// build scripts in front of the dylib search path, like `cargo run`.
+fn run(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let mut paths: Vec<std::path::PathBuf> = matches
+        .get_many::<Utf8PathBuf>("library-path")
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.clone().into_std_path_buf())
+        .collect();
+    if let Some(target_dir) = matches.get_one::<Utf8PathBuf>("target-dir") {
+        for file in matches
+            .get_many::<Utf8PathBuf>("build-script-output")
+            .into_iter()
+            .flatten()
+        {
+            let output = parse_output(file, "")?;
+            paths.extend(runtime_library_paths(&output, target_dir.as_std_path()));
+        }
+    }
+    let var = dylib_path_envvar();
+    if let Some(existing) = std::env::var_os(var) {
+        paths.extend(std::env::split_paths(&existing));
+    }
+
+    let mut cmd = command(matches);
+    cmd.env(var, std::env::join_paths(paths)?);
+    Ok(cmd.status()?)
+}
+
+/// The `ns/iter` of each benchmark by name.
+type BenchResults = BTreeMap<String, f64>;
+
+pub const BENCH_LATEST: &str = "latest.json";
+const BENCH_BASELINE: &str = "baseline.json";
+
+/// Runs a libtest bench binary and saves its output as `<nanos>.txt` and the
+/// results as `<nanos>.json` in `--dir`, copied to `latest.json`.
+///
+/// The first results are also kept as `baseline.json`, remove it to take the
+/// next run as the baseline.
+fn bench(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let dir = matches
+        .get_one::<Utf8PathBuf>("dir")
+        .ok_or(anyhow::format_err!("--dir is required"))?;
+    std::fs::create_dir_all(dir)?;
+    let output = command(matches)
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    std::io::stdout().write_all(&output.stdout)?;
+    if !output.status.success() {
+        return Ok(output.status);
+    }
+
+    // nanoseconds, so that runs within the same second do not overwrite
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    std::fs::write(dir.join(format!("{nanos}.txt")), &output.stdout)?;
+    let results = parse_bench_results(&String::from_utf8_lossy(&output.stdout));
+    let json = serde_json::to_vec_pretty(&results)?;
+    std::fs::write(dir.join(format!("{nanos}.json")), &json)?;
+    std::fs::write(dir.join(BENCH_LATEST), &json)?;
+    let baseline = dir.join(BENCH_BASELINE);
+    if !baseline.exists() {
+        std::fs::write(baseline, &json)?;
+    }
+    Ok(output.status)
+}
+
+/// Parses the `test NAME ... bench: 1,234 ns/iter (+/- 56)` lines of libtest.
+fn parse_bench_results(output: &str) -> BenchResults {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, result) = line.strip_prefix("test ")?.split_once(" ... bench:")?;
+            let (ns, _) = result.trim_start().split_once(" ns/iter")?;
+            Some((name.trim().to_string(), ns.replace(',', "").parse().ok()?))
+        })
+        .collect()
+}
+
+/// Reports the benchmarks whose latest results are slower than their
+/// baseline by more than `--threshold` percent, failing if there are any.
+fn bench_compare(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let threshold = matches.get_one::<f64>("threshold").copied().unwrap_or(5.0);
+    let read = |file: Utf8PathBuf| -> anyhow::Result<Option<BenchResults>> {
+        match std::fs::read(&file) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(_) => Ok(None),
+        }
+    };
+    let mut regressions = 0;
+    for dir in matches.get_many::<Utf8PathBuf>("DIR").into_iter().flatten() {
+        let (Some(latest), Some(baseline)) = (
+            read(dir.join(BENCH_LATEST))?,
+            read(dir.join(BENCH_BASELINE))?,
+        ) else {
+            eprintln!("no bench results in {dir}");
+            continue;
+        };
+        for (name, ns) in &latest {
+            let Some(base) = baseline.get(name).filter(|base| **base > 0.0) else {
+                continue;
+            };
+            let change = (ns - base) / base * 100.0;
+            if change > threshold {
+                regressions += 1;
+                println!("{name}: {base} -> {ns} ns/iter (+{change:.1}%)");
+            }
+        }
+    }
+    if regressions > 0 {
+        anyhow::bail!("{regressions} benchmarks regressed by more than {threshold}%");
+    }
+    Ok(ExitStatus::default())
+}
+
+/// Rewrites an `env-check` snapshot, only when its values changed so ninja
+/// sees a new mtime exactly when the build script has to rerun.
+fn write_env_snapshot(file: &Utf8Path, snapshot: &EnvSnapshot) -> anyhow::Result<()> {
+    let contents = serde_json::to_vec_pretty(snapshot)?;
+    if std::fs::read(file).is_ok_and(|old| old == contents) {
+        return Ok(());
+    }
+    std::fs::write(file, contents)?;
+    Ok(())
+}
+
+/// Compares the variables recorded in `--snapshot` with the current
+/// environment and rewrites the snapshot when they differ.
+fn env_check(matches: &ArgMatches) -> anyhow::Result<ExitStatus> {
+    let file = matches
+        .get_one::<Utf8PathBuf>("snapshot")
+        .ok_or(anyhow::format_err!("--snapshot is required"))?;
+    let recorded: EnvSnapshot = match std::fs::read(file) {
+        Ok(contents) => serde_json::from_slice(&contents)?,
+        Err(_) => EnvSnapshot::new(),
+    };
+    write_env_snapshot(file, &env_snapshot(recorded.into_keys()))?;
+    Ok(ExitStatus::default())
+}
@@ -0,0 +1,990 @@
+//! A parser for Cargo's `--unit-graph` output.
+//!
+//! The main type is [`UnitGraph`]. Unlike `--build-plan`, the unit graph only
+//! describes what cargo would build, not how. [`UnitGraph::into_build_plan`]
+//! rebuilds the rustc and build script command lines from the unit data,
+//! using the same output layout as [`BuildPlan::from_cargo_output`].
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/unstable.html#unit-graph>.
+//!
+//! [`BuildPlan::from_cargo_output`]: crate::build_plan::BuildPlan::from_cargo_output
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::Package;
+use cargo_platform::{Cfg, Platform};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX, EXE_SUFFIX};
+use std::hash::{Hash, Hasher};
+use std::process::Command;
+
+use crate::build_plan::{
+    cargo_config_files, BuildPlan, CompileMode, Invocation, StableHasher, TargetKind, METADATA,
+};
+use crate::cli;
+use crate::crate_type::CrateType;
+use crate::custom_build::envify;
+
+/// A unit graph output by `cargo build --unit-graph`.
+#[derive(Debug, Deserialize)]
+pub struct UnitGraph {
+    pub version: u32,
+    pub units: Vec<Unit>,
+    /// Indices of the units requested on the command line.
+    pub roots: Vec<usize>,
+}
+
+/// A target of a package, compiled or run in a given mode.
+#[derive(Debug, Deserialize)]
+pub struct Unit {
+    pub pkg_id: String,
+    pub target: Target,
+    pub profile: Profile,
+    /// The target triple, `None` for the host.
+    pub platform: Option<String>,
+    pub mode: CompileMode,
+    pub features: Vec<String>,
+    pub dependencies: Vec<UnitDep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    pub kind: TargetKind,
+    pub crate_types: Vec<String>,
+    pub name: String,
+    pub src_path: Utf8PathBuf,
+    pub edition: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub opt_level: String,
+    pub lto: serde_json::Value,
+    pub codegen_units: Option<u32>,
+    pub debuginfo: Option<serde_json::Value>,
+    pub debug_assertions: bool,
+    pub overflow_checks: bool,
+    pub rpath: bool,
+    pub incremental: bool,
+    pub panic: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnitDep {
+    pub index: usize,
+    pub extern_crate_name: String,
+    #[serde(default)]
+    pub noprelude: bool,
+}
+
+/// Files produced by a unit, laid out the way cargo does.
+#[derive(Debug, Default)]
+struct Artifacts {
+    /// Directory passed as `--out-dir`, or `OUT_DIR` of a build script run.
+    out_dir: Utf8PathBuf,
+    outputs: Vec<Utf8PathBuf>,
+    links: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+    /// The file passed to dependents with `--extern`.
+    extern_path: Option<Utf8PathBuf>,
+}
+
+/// The bits of `rustc` needed to rebuild command lines.
+#[derive(Debug)]
+struct Rustc {
+    program: String,
+    version: String,
+    host: String,
+    cfgs: BTreeMap<Option<String>, Vec<String>>,
+    rustflags: BTreeMap<Option<String>, Vec<String>>,
+}
+
+impl Rustc {
+    fn new() -> anyhow::Result<Self> {
+        let program = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+        let version = stdout(Command::new(&program).arg("-vV"))?;
+        let host = version
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .ok_or(anyhow::format_err!("failed to find host in `rustc -vV`"))?
+            .to_string();
+        Ok(Rustc {
+            program,
+            version,
+            host,
+            cfgs: BTreeMap::new(),
+            rustflags: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the output of `rustc --print cfg` for `target`.
+    fn cfgs(&mut self, target: Option<&String>) -> anyhow::Result<&Vec<String>> {
+        if !self.cfgs.contains_key(&target.cloned()) {
+            let mut cmd = Command::new(&self.program);
+            cmd.arg("--print").arg("cfg");
+            if let Some(target) = target {
+                cmd.arg("--target").arg(target);
+            }
+            let cfgs = stdout(&mut cmd)?.lines().map(ToString::to_string).collect();
+            self.cfgs.insert(target.cloned(), cfgs);
+        }
+        Ok(&self.cfgs[&target.cloned()])
+    }
+
+    /// Returns the flags cargo passes to every rustc compiling for `target`:
+    /// `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS`, then `target.<triple>.rustflags`
+    /// or `build.rustflags` of the config files. Like cargo, host units get
+    /// none when cross compiling with `--target`.
+    fn rustflags(&mut self, target: Option<&String>) -> anyhow::Result<&Vec<String>> {
+        if !self.rustflags.contains_key(&target.cloned()) {
+            let flags = if target.is_none() && !cli::targets().is_empty() {
+                Vec::new()
+            } else {
+                let cfgs = self
+                    .cfgs(target)?
+                    .iter()
+                    .map(|cfg| cfg.parse())
+                    .collect::<Result<Vec<Cfg>, _>>()?;
+                config_rustflags(target.unwrap_or(&self.host), &cfgs)?
+            };
+            self.rustflags.insert(target.cloned(), flags);
+        }
+        Ok(&self.rustflags[&target.cloned()])
+    }
+}
+
+/// The rustflags of the environment or of the cargo config files for `triple`,
+/// whose `rustc --print cfg` is `cfgs`.
+fn config_rustflags(triple: &str, cfgs: &[Cfg]) -> anyhow::Result<Vec<String>> {
+    if let Ok(flags) = std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+        return Ok(flags
+            .split('\x1f')
+            .filter(|flag| !flag.is_empty())
+            .map(ToString::to_string)
+            .collect());
+    }
+    if let Ok(flags) = std::env::var("RUSTFLAGS") {
+        return Ok(flags.split_whitespace().map(ToString::to_string).collect());
+    }
+    let flags = |value: &toml::Value| -> Vec<String> {
+        match value {
+            toml::Value::String(flags) => {
+                flags.split_whitespace().map(ToString::to_string).collect()
+            }
+            toml::Value::Array(flags) => flags
+                .iter()
+                .filter_map(|flag| flag.as_str())
+                .map(ToString::to_string)
+                .collect(),
+            _ => Vec::new(),
+        }
+    };
+    let mut target_flags = Vec::new();
+    let mut build_flags = Vec::new();
+    // Arrays of several files are joined, the deepest directory last.
+    for file in cargo_config_files()
+        .iter()
+        .rev()
+        .filter(|file| file.exists())
+    {
+        let config: toml::Table = std::fs::read_to_string(file)?.parse()?;
+        let targets = config.get("target").and_then(|targets| targets.as_table());
+        for (key, target) in targets.into_iter().flatten() {
+            let Some(value) = target.get("rustflags") else {
+                continue;
+            };
+            // `target.<triple>` and every matching `target.'cfg(..)'`
+            let platform: Platform = key
+                .parse()
+                .map_err(|e| anyhow::format_err!("`target.'{key}'` in {file}: {e}"))?;
+            if platform.matches(triple, cfgs) {
+                target_flags.extend(flags(value));
+            }
+        }
+        if let Some(value) = config.get("build").and_then(|build| build.get("rustflags")) {
+            build_flags.extend(flags(value));
+        }
+    }
+    Ok(match target_flags.is_empty() {
+        true => build_flags,
+        false => target_flags,
+    })
+}
+
+/// The `harness` key of `target` in the manifest, if set.
+fn manifest_harness(manifest_path: &Utf8Path, target: &Target) -> Option<bool> {
+    let manifest: toml::Table = std::fs::read_to_string(manifest_path).ok()?.parse().ok()?;
+    let section = match target.kind {
+        TargetKind::Lib(..) => return manifest.get("lib")?.get("harness")?.as_bool(),
+        TargetKind::Bin => "bin",
+        TargetKind::Test => "test",
+        TargetKind::Bench => "bench",
+        TargetKind::ExampleBin | TargetKind::ExampleLib(..) => "example",
+        TargetKind::CustomBuild => return None,
+    };
+    manifest
+        .get(section)?
+        .as_array()?
+        .iter()
+        .find(|t| t.get("name").and_then(|name| name.as_str()) == Some(target.name.as_str()))?
+        .get("harness")?
+        .as_bool()
+}
+
+fn stdout(cmd: &mut Command) -> anyhow::Result<String> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        let error = String::from_utf8(output.stderr)?;
+        return Err(anyhow::format_err!("{cmd:?} failed: {error}"));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Environment variables cargo sets for every invocation in a package.
+pub(crate) fn package_env(pkg: &Package) -> BTreeMap<String, String> {
+    let version = &pkg.version;
+    let manifest_dir = pkg.manifest_path.parent().unwrap_or(&pkg.manifest_path);
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    [
+        ("CARGO_MANIFEST_DIR", manifest_dir.to_string()),
+        ("CARGO_MANIFEST_PATH", pkg.manifest_path.to_string()),
+        ("CARGO_PKG_NAME", pkg.name.clone()),
+        ("CARGO_PKG_VERSION", version.to_string()),
+        ("CARGO_PKG_VERSION_MAJOR", version.major.to_string()),
+        ("CARGO_PKG_VERSION_MINOR", version.minor.to_string()),
+        ("CARGO_PKG_VERSION_PATCH", version.patch.to_string()),
+        ("CARGO_PKG_VERSION_PRE", version.pre.to_string()),
+        ("CARGO_PKG_AUTHORS", pkg.authors.join(":")),
+        ("CARGO_PKG_DESCRIPTION", optional(pkg.description.clone())),
+        ("CARGO_PKG_HOMEPAGE", optional(pkg.homepage.clone())),
+        ("CARGO_PKG_REPOSITORY", optional(pkg.repository.clone())),
+        ("CARGO_PKG_LICENSE", optional(pkg.license.clone())),
+        (
+            "CARGO_PKG_LICENSE_FILE",
+            optional(pkg.license_file.as_ref().map(ToString::to_string)),
+        ),
+        (
+            "CARGO_PKG_RUST_VERSION",
+            optional(pkg.rust_version.as_ref().map(ToString::to_string)),
+        ),
+        (
+            "CARGO_PKG_README",
+            optional(pkg.readme.as_ref().map(ToString::to_string)),
+        ),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect()
+}
+
+/// Turns `rustc --print cfg` lines into `CARGO_CFG_*` variables for build scripts.
+fn cfg_env(cfgs: &[String], debug_assertions: bool) -> BTreeMap<String, String> {
+    let mut values: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for cfg in cfgs {
+        let (key, value) = match cfg.split_once('=') {
+            Some((key, value)) => (key, Some(value.trim_matches('"'))),
+            None => (cfg.as_str(), None),
+        };
+        // cargo reports this one from the profile, not from rustc's defaults
+        if key == "debug_assertions" {
+            continue;
+        }
+        let entry = values
+            .entry(format!("CARGO_CFG_{}", envify(key)))
+            .or_default();
+        entry.extend(value);
+    }
+    if debug_assertions {
+        values
+            .entry("CARGO_CFG_DEBUG_ASSERTIONS".to_string())
+            .or_default();
+    }
+    values
+        .into_iter()
+        .map(|(key, values)| (key, values.join(",")))
+        .collect()
+}
+
+impl Unit {
+    fn crate_name(&self) -> String {
+        self.target.name.replace('-', "_")
+    }
+
+    fn crate_types(&self) -> Vec<CrateType> {
+        self.target
+            .crate_types
+            .iter()
+            .map(CrateType::from)
+            .collect()
+    }
+
+    /// Path dependencies get lints, everything else is capped.
+    fn is_local(&self) -> bool {
+        self.pkg_id.contains("path+file://")
+    }
+
+    fn package(&self) -> anyhow::Result<&'static Package> {
+        METADATA
+            .packages
+            .iter()
+            .find(|p| p.id.repr == self.pkg_id)
+            .ok_or(anyhow::format_err!(
+                "failed to find package {}",
+                self.pkg_id
+            ))
+    }
+
+    /// Whether the target is compiled with `--test`.
+    fn is_test(&self) -> bool {
+        matches!(
+            self.mode,
+            CompileMode::Test | CompileMode::Bench | CompileMode::Check { test: true }
+        )
+    }
+
+    /// Whether the target uses the libtest harness, which `harness = false`
+    /// turns off. The unit graph leaves this out, it is read from the manifest.
+    fn harness(&self) -> bool {
+        self.package()
+            .ok()
+            .and_then(|pkg| manifest_harness(&pkg.manifest_path, &self.target))
+            .unwrap_or(true)
+    }
+
+    fn is_build_script_compile(&self) -> bool {
+        self.target.kind == TargetKind::CustomBuild && self.mode != CompileMode::RunCustomBuild
+    }
+
+    fn lto(&self) -> String {
+        match &self.profile.lto {
+            serde_json::Value::String(s) => s.clone(),
+            value => value.to_string(),
+        }
+    }
+
+    fn debuginfo(&self) -> Option<String> {
+        match self.profile.debuginfo.as_ref()? {
+            serde_json::Value::Number(n) if n.as_u64() == Some(0) => None,
+            serde_json::Value::String(s) if s == "none" => None,
+            serde_json::Value::String(s) => Some(s.clone()),
+            value => Some(value.to_string()),
+        }
+    }
+
+    /// The directory outputs of this unit go to, i.e. the equivalent of
+    /// `target/<triple>/<profile>/`, minus the profile part unless
+    /// `--cargo-layout`.
+    fn dest_dir(&self, build_dir: &Utf8Path) -> Utf8PathBuf {
+        self.profile_dir(&self.target_dir(build_dir))
+    }
+
+    /// The equivalent of `target/<triple>/`, where cargo puts the docs.
+    fn target_dir(&self, build_dir: &Utf8Path) -> Utf8PathBuf {
+        match &self.platform {
+            Some(triple) => build_dir.join(triple),
+            None => build_dir.to_path_buf(),
+        }
+    }
+
+    /// `dir/<profile>` with `--cargo-layout`, named like cargo's profile dirs.
+    fn profile_dir(&self, dir: &Utf8Path) -> Utf8PathBuf {
+        if !cli::cargo_layout() {
+            return dir.to_path_buf();
+        }
+        match self.profile.name.as_str() {
+            "dev" | "test" => dir.join("debug"),
+            "bench" => dir.join("release"),
+            name => dir.join(name),
+        }
+    }
+
+    fn artifacts(&self, meta: &str, is_root: bool, build_dir: &Utf8Path) -> Artifacts {
+        let dest = self.dest_dir(build_dir);
+        let crate_name = self.crate_name();
+        let stem = format!("{crate_name}-{meta}");
+
+        if self.mode == CompileMode::RunCustomBuild {
+            let pkg_dir = dest
+                .join("build")
+                .join(format!("{}-{meta}", self.pkg_name()));
+            return Artifacts {
+                out_dir: pkg_dir.join("out"),
+                ..Default::default()
+            };
+        }
+        if self.is_build_script_compile() {
+            let out_dir = dest
+                .join("build")
+                .join(format!("{}-{meta}", self.pkg_name()));
+            let output = out_dir.join(format!("{stem}{EXE_SUFFIX}"));
+            let link = out_dir.join(format!("{}{EXE_SUFFIX}", self.target.name));
+            return Artifacts {
+                out_dir,
+                outputs: vec![output.clone()],
+                links: BTreeMap::from([(link, output)]),
+                extern_path: None,
+            };
+        }
+
+        if matches!(self.mode, CompileMode::Doc { .. }) {
+            // rustdoc writes `<crate>/index.html` and shared files to `doc`
+            let out_dir = self.target_dir(build_dir).join("doc");
+            return Artifacts {
+                outputs: vec![out_dir.join(&crate_name).join("index.html")],
+                out_dir,
+                ..Default::default()
+            };
+        }
+
+        let (out_dir, uplift_dir) = match self.target.kind {
+            TargetKind::ExampleBin | TargetKind::ExampleLib(_) => {
+                (dest.join("examples"), dest.join("examples"))
+            }
+            _ => (dest.join("deps"), dest.clone()),
+        };
+        let mut artifacts = Artifacts {
+            out_dir: out_dir.clone(),
+            ..Default::default()
+        };
+
+        match self.mode {
+            CompileMode::Check { .. } => {
+                let rmeta = out_dir.join(format!("lib{stem}.rmeta"));
+                artifacts.outputs.push(rmeta.clone());
+                artifacts.extern_path = Some(rmeta);
+            }
+            CompileMode::Test | CompileMode::Bench => {
+                artifacts
+                    .outputs
+                    .push(out_dir.join(format!("{stem}{EXE_SUFFIX}")));
+            }
+            _ => {
+                for crate_type in self.crate_types() {
+                    let (prefix, suffix) = match crate_type {
+                        CrateType::Bin => ("", EXE_SUFFIX),
+                        CrateType::Lib | CrateType::Rlib => ("lib", ".rlib"),
+                        CrateType::Dylib | CrateType::Cdylib | CrateType::ProcMacro => {
+                            (DLL_PREFIX, DLL_SUFFIX)
+                        }
+                        CrateType::Staticlib if cfg!(target_family = "windows") => ("", ".lib"),
+                        CrateType::Staticlib => ("lib", ".a"),
+                        CrateType::Other(_) => continue,
+                    };
+                    let output = out_dir.join(format!("{prefix}{stem}{suffix}"));
+                    artifacts.outputs.push(output.clone());
+
+                    let uplift = match crate_type {
+                        CrateType::Bin => true,
+                        CrateType::Dylib => true,
+                        _ => is_root,
+                    };
+                    if uplift {
+                        let name = match crate_type {
+                            CrateType::Bin => &self.target.name,
+                            _ => &crate_name,
+                        };
+                        let link = uplift_dir.join(format!("{prefix}{name}{suffix}"));
+                        artifacts.links.insert(link, output.clone());
+                    }
+
+                    if crate_type.is_linkable() && artifacts.extern_path.is_none() {
+                        artifacts.extern_path = Some(output);
+                    }
+                    if matches!(crate_type, CrateType::Lib | CrateType::Rlib) {
+                        artifacts
+                            .outputs
+                            .push(out_dir.join(format!("lib{stem}.rmeta")));
+                    }
+                }
+            }
+        }
+        artifacts
+    }
+
+    fn pkg_name(&self) -> String {
+        self.package()
+            .map(|p| p.name.clone())
+            .unwrap_or(self.target.name.clone())
+    }
+
+    fn emit(&self) -> &'static str {
+        match self.mode {
+            CompileMode::Check { .. } => "dep-info,metadata",
+            CompileMode::Build
+                if self
+                    .crate_types()
+                    .iter()
+                    .any(|k| matches!(k, CrateType::Lib | CrateType::Rlib)) =>
+            {
+                "dep-info,metadata,link"
+            }
+            _ => "dep-info,link",
+        }
+    }
+
+    /// Codegen flags derived from the profile, as cargo's `build_base_args`.
+    fn profile_args(&self) -> Vec<String> {
+        let profile = &self.profile;
+        let mut args = Vec::new();
+        let mut codegen = |opt: String| {
+            args.push("-C".to_string());
+            args.push(opt);
+        };
+        let default_debug_assertions = profile.opt_level == "0";
+        if !default_debug_assertions {
+            codegen(format!("opt-level={}", profile.opt_level));
+        }
+        let is_test = self.is_test();
+        let can_panic_abort = !is_test
+            && self.target.kind != TargetKind::CustomBuild
+            && !self.crate_types().contains(&CrateType::ProcMacro);
+        if profile.panic == "abort" && can_panic_abort {
+            codegen("panic=abort".to_string());
+        }
+        match self.lto().as_str() {
+            "false" => codegen("embed-bitcode=no".to_string()),
+            "off" => codegen("lto=off".to_string()),
+            lto if self.crate_types().iter().any(|k| k.can_lto()) => match lto {
+                "true" | "fat" => codegen("lto".to_string()),
+                lto => codegen(format!("lto={lto}")),
+            },
+            _ => {}
+        }
+        if let Some(n) = profile.codegen_units {
+            codegen(format!("codegen-units={n}"));
+        }
+        if let Some(debuginfo) = self.debuginfo() {
+            codegen(format!("debuginfo={debuginfo}"));
+        }
+        if profile.debug_assertions != default_debug_assertions {
+            let on = if profile.debug_assertions {
+                "on"
+            } else {
+                "off"
+            };
+            codegen(format!("debug-assertions={on}"));
+        }
+        if profile.overflow_checks != profile.debug_assertions {
+            let on = if profile.overflow_checks { "on" } else { "off" };
+            codegen(format!("overflow-checks={on}"));
+        }
+        if profile.rpath {
+            codegen("rpath".to_string());
+        }
+        args
+    }
+}
+
+impl UnitGraph {
+    /// Returns the `-C metadata` hash of every unit.
+    ///
+    /// Like cargo's, it covers everything that changes the artifact, including
+    /// the hashes of dependencies, so that units never collide in `deps/`.
+    fn metadata(&self, rustc: &Rustc, rustflags: &[Vec<String>]) -> Vec<String> {
+        fn visit(
+            graph: &UnitGraph,
+            index: usize,
+            rustc: &Rustc,
+            rustflags: &[Vec<String>],
+            metas: &mut Vec<Option<String>>,
+        ) -> String {
+            if let Some(meta) = &metas[index] {
+                return meta.clone();
+            }
+            let unit = &graph.units[index];
+            let mut deps: Vec<String> = unit
+                .dependencies
+                .iter()
+                .map(|dep| visit(graph, dep.index, rustc, rustflags, metas))
+                .collect();
+            deps.sort();
+
+            let mut hasher = StableHasher::default();
+            unit.pkg_id.hash(&mut hasher);
+            unit.target.name.hash(&mut hasher);
+            unit.target.kind.hash(&mut hasher);
+            unit.target.crate_types.hash(&mut hasher);
+            unit.mode.hash(&mut hasher);
+            format!("{:?}", unit.profile).hash(&mut hasher);
+            unit.platform.hash(&mut hasher);
+            unit.features.hash(&mut hasher);
+            deps.hash(&mut hasher);
+            rustc.version.hash(&mut hasher);
+            rustflags[index].hash(&mut hasher);
+            let meta = format!("{:016x}", hasher.finish());
+            metas[index] = Some(meta.clone());
+            meta
+        }
+
+        let mut metas = vec![None; self.units.len()];
+        (0..self.units.len())
+            .map(|i| visit(self, i, rustc, rustflags, &mut metas))
+            .collect()
+    }
+
+    /// Restores `Check { test: true }`, which cargo serializes as plain
+    /// `check`.
+    ///
+    /// Tests and benches are always checked with `--test`. `cargo check
+    /// --tests` also checks libs and bins both with and without it. The units
+    /// are sorted, so the second of two otherwise equal check units is the
+    /// test one.
+    fn mark_check_tests(&mut self) {
+        let mut seen = BTreeSet::new();
+        for unit in &mut self.units {
+            if !matches!(unit.mode, CompileMode::Check { .. }) {
+                continue;
+            }
+            let key = (
+                unit.pkg_id.clone(),
+                unit.target.name.clone(),
+                unit.target.kind.clone(),
+                unit.platform.clone(),
+                unit.features.clone(),
+            );
+            let harness = matches!(unit.target.kind, TargetKind::Test | TargetKind::Bench);
+            if !seen.insert(key) || harness {
+                unit.mode = CompileMode::Check { test: true };
+            }
+        }
+    }
+
+    /// Rebuilds the invocations cargo would run for this graph, with outputs
+    /// placed in `build_dir`.
+    pub fn into_build_plan(mut self, build_dir: &Utf8Path) -> anyhow::Result<BuildPlan> {
+        if self.version != 1 {
+            return Err(anyhow::format_err!(
+                "unsupported unit graph version {}",
+                self.version
+            ));
+        }
+        self.mark_check_tests();
+        let mut rustc = Rustc::new()?;
+        let rustflags = self
+            .units
+            .iter()
+            .map(|unit| rustc.rustflags(unit.platform.as_ref()).cloned())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let metas = self.metadata(&rustc, &rustflags);
+        let root_pkgs: BTreeSet<&String> =
+            self.roots.iter().map(|i| &self.units[*i].pkg_id).collect();
+        let artifacts: Vec<Artifacts> = self
+            .units
+            .iter()
+            .enumerate()
+            .map(|(i, unit)| unit.artifacts(&metas[i], self.roots.contains(&i), build_dir))
+            .collect();
+
+        let mut invocations = Vec::with_capacity(self.units.len());
+        let mut inputs = BTreeSet::new();
+        for (i, unit) in self.units.iter().enumerate() {
+            let pkg = unit.package()?;
+            inputs.insert(pkg.manifest_path.clone());
+            let pkg_dir = pkg
+                .manifest_path
+                .parent()
+                .unwrap_or(&pkg.manifest_path)
+                .to_path_buf();
+
+            let mut env = package_env(pkg);
+            let run_custom_build = unit
+                .dependencies
+                .iter()
+                .find(|dep| self.units[dep.index].mode == CompileMode::RunCustomBuild);
+            if let Some(dep) = run_custom_build {
+                env.insert(
+                    "OUT_DIR".to_string(),
+                    artifacts[dep.index].out_dir.to_string(),
+                );
+            }
+
+            let invocation = if unit.mode == CompileMode::RunCustomBuild {
+                let program = unit
+                    .dependencies
+                    .iter()
+                    .find(|dep| self.units[dep.index].is_build_script_compile())
+                    .and_then(|dep| artifacts[dep.index].links.keys().next())
+                    .ok_or(anyhow::format_err!(
+                        "failed to find build script of {}",
+                        unit.pkg_id
+                    ))?;
+                let out_dir = &artifacts[i].out_dir;
+                let host = rustc.host.clone();
+                let target = unit.platform.clone().unwrap_or(host.clone());
+                let profile = match unit.profile.name.as_str() {
+                    "dev" | "test" => "debug",
+                    "release" | "bench" => "release",
+                    _ if unit.profile.opt_level == "0" => "debug",
+                    _ => "release",
+                };
+                let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+                env.insert("OUT_DIR".to_string(), out_dir.to_string());
+                env.insert("TARGET".to_string(), target);
+                env.insert("HOST".to_string(), host);
+                env.insert("NUM_JOBS".to_string(), jobs.to_string());
+                env.insert("OPT_LEVEL".to_string(), unit.profile.opt_level.clone());
+                env.insert("DEBUG".to_string(), unit.debuginfo().is_some().to_string());
+                env.insert("PROFILE".to_string(), profile.to_string());
+                env.insert("RUSTC".to_string(), rustc.program.clone());
+                env.insert(
+                    "RUSTDOC".to_string(),
+                    std::env::var("RUSTDOC").unwrap_or("rustdoc".to_string()),
+                );
+                env.insert(
+                    "CARGO".to_string(),
+                    std::env::var("CARGO").unwrap_or("cargo".to_string()),
+                );
+                env.insert(
+                    "CARGO_ENCODED_RUSTFLAGS".to_string(),
+                    rustflags[i].join("\x1f"),
+                );
+                if let Some(links) = &pkg.links {
+                    env.insert("CARGO_MANIFEST_LINKS".to_string(), links.clone());
+                }
+                for feature in &unit.features {
+                    env.insert(
+                        format!("CARGO_FEATURE_{}", envify(feature)),
+                        "1".to_string(),
+                    );
+                }
+                let cfgs = rustc.cfgs(unit.platform.as_ref())?;
+                env.extend(cfg_env(cfgs, unit.profile.debug_assertions));
+
+                Invocation {
+                    package_name: pkg.name.clone(),
+                    package_version: pkg.version.to_string(),
+                    target_kind: unit.target.kind.clone(),
+                    compile_mode: unit.mode,
+                    deps: unit.dependencies.iter().map(|dep| dep.index).collect(),
+                    outputs: Vec::new(),
+                    links: BTreeMap::new(),
+                    program: program.to_string(),
+                    args: Vec::new(),
+                    env,
+                    cwd: Some(pkg_dir),
+                }
+            } else {
+                env.insert("CARGO_CRATE_NAME".to_string(), unit.crate_name());
+                if unit.target.kind == TargetKind::Bin {
+                    env.insert("CARGO_BIN_NAME".to_string(), unit.target.name.clone());
+                }
+                if root_pkgs.contains(&unit.pkg_id) {
+                    env.insert("CARGO_PRIMARY_PACKAGE".to_string(), "1".to_string());
+                }
+                if matches!(unit.mode, CompileMode::Test | CompileMode::Bench) {
+                    let tmp = build_dir.join("tmp");
+                    env.insert("CARGO_TARGET_TMPDIR".to_string(), tmp.to_string());
+                }
+                if matches!(unit.target.kind, TargetKind::Test | TargetKind::Bench) {
+                    for dep in &unit.dependencies {
+                        let bin = &self.units[dep.index];
+                        if bin.target.kind != TargetKind::Bin {
+                            continue;
+                        }
+                        let artifacts = &artifacts[dep.index];
+                        let exe = artifacts.links.keys().next().or(artifacts.outputs.first());
+                        if let Some(exe) = exe {
+                            env.insert(
+                                format!("CARGO_BIN_EXE_{}", bin.target.name),
+                                exe.to_string(),
+                            );
+                        }
+                    }
+                }
+                let (program, args) = match unit.mode {
+                    CompileMode::Doc { .. } => (
+                        std::env::var("RUSTDOC").unwrap_or("rustdoc".to_string()),
+                        self.rustdoc_args(i, &metas[i], &artifacts, pkg, build_dir),
+                    ),
+                    _ => (
+                        rustc.program.clone(),
+                        self.rustc_args(i, &metas[i], &artifacts, build_dir)
+                            .into_iter()
+                            .chain(rustflags[i].iter().cloned())
+                            .collect(),
+                    ),
+                };
+
+                Invocation {
+                    package_name: pkg.name.clone(),
+                    package_version: pkg.version.to_string(),
+                    target_kind: unit.target.kind.clone(),
+                    compile_mode: unit.mode,
+                    deps: unit.dependencies.iter().map(|dep| dep.index).collect(),
+                    outputs: artifacts[i].outputs.clone(),
+                    links: artifacts[i].links.clone(),
+                    program,
+                    args,
+                    env,
+                    cwd: Some(METADATA.workspace_root.clone()),
+                }
+            };
+            invocations.push(invocation);
+        }
+
+        Ok(BuildPlan {
+            invocations,
+            inputs: inputs.into_iter().collect(),
+        })
+    }
+
+    /// The arguments of cargo's `prepare_rustdoc`, with the dep-info cargo
+    /// requests under `-Zrustdoc-depinfo` so ninja knows the sources.
+    fn rustdoc_args(
+        &self,
+        index: usize,
+        meta: &str,
+        artifacts: &[Artifacts],
+        pkg: &Package,
+        build_dir: &Utf8Path,
+    ) -> Vec<String> {
+        let unit = &self.units[index];
+        let doc_dir = &artifacts[index].out_dir;
+        let mut args = vec![format!("--edition={}", unit.target.edition)];
+        for crate_type in unit.crate_types() {
+            args.push("--crate-type".to_string());
+            args.push(crate_type.to_string());
+        }
+        args.extend([
+            "--crate-name".to_string(),
+            unit.crate_name(),
+            unit.target.src_path.to_string(),
+            "-o".to_string(),
+            doc_dir.to_string(),
+            "-Zunstable-options".to_string(),
+            format!(
+                "--emit=toolchain-shared-resources,invocation-specific,dep-info={}",
+                doc_dir.join(format!("{}-{meta}.d", unit.crate_name()))
+            ),
+            "--error-format=json".to_string(),
+            "--json=diagnostic-rendered-ansi".to_string(),
+        ]);
+        if let Some(triple) = &unit.platform {
+            args.push("--target".to_string());
+            args.push(triple.clone());
+        }
+        if unit.target.kind == TargetKind::Bin {
+            args.push("--document-private-items".to_string());
+            args.push("-Arustdoc::private-intra-doc-links".to_string());
+        }
+        for feature in &unit.features {
+            args.push("--cfg".to_string());
+            args.push(format!("feature=\"{feature}\""));
+        }
+        args.push("-C".to_string());
+        args.push(format!("metadata={meta}"));
+        args.push("-L".to_string());
+        args.push(format!(
+            "dependency={}",
+            unit.dest_dir(build_dir).join("deps")
+        ));
+        for dep in &unit.dependencies {
+            if let Some(path) = &artifacts[dep.index].extern_path {
+                args.push("--extern".to_string());
+                args.push(format!("{}={path}", dep.extern_crate_name));
+            }
+        }
+        args.push("--crate-version".to_string());
+        args.push(pkg.version.to_string());
+        if !unit.is_local() {
+            args.push("--cap-lints".to_string());
+            args.push("allow".to_string());
+        }
+        args
+    }
+
+    fn rustc_args(
+        &self,
+        index: usize,
+        meta: &str,
+        artifacts: &[Artifacts],
+        build_dir: &Utf8Path,
+    ) -> Vec<String> {
+        let unit = &self.units[index];
+        let mut args = vec![
+            "--crate-name".to_string(),
+            unit.crate_name(),
+            format!("--edition={}", unit.target.edition),
+            unit.target.src_path.to_string(),
+            "--error-format=json".to_string(),
+            "--json=diagnostic-rendered-ansi,artifacts,future-incompat".to_string(),
+        ];
+        let is_test = unit.is_test();
+        if !is_test {
+            for crate_type in unit.crate_types() {
+                args.push("--crate-type".to_string());
+                args.push(crate_type.to_string());
+            }
+        }
+        args.push(format!("--emit={}", unit.emit()));
+        args.extend(unit.profile_args());
+        if is_test && unit.harness() {
+            args.push("--test".to_string());
+        }
+        for feature in &unit.features {
+            args.push("--cfg".to_string());
+            args.push(format!("feature=\"{feature}\""));
+        }
+        args.push("-C".to_string());
+        args.push(format!("metadata={meta}"));
+        args.push("-C".to_string());
+        args.push(format!("extra-filename=-{meta}"));
+        args.push("--out-dir".to_string());
+        args.push(artifacts[index].out_dir.to_string());
+        if let Some(triple) = &unit.platform {
+            args.push("--target".to_string());
+            args.push(triple.clone());
+        }
+        let dest = unit.dest_dir(build_dir);
+        if unit.profile.incremental {
+            args.push("-C".to_string());
+            args.push(format!("incremental={}", dest.join("incremental")));
+        }
+        args.push("-L".to_string());
+        args.push(format!("dependency={}", dest.join("deps")));
+        if unit.platform.is_some() {
+            args.push("-L".to_string());
+            args.push(format!(
+                "dependency={}",
+                unit.profile_dir(build_dir).join("deps")
+            ));
+        }
+        for dep in &unit.dependencies {
+            let dep_unit = &self.units[dep.index];
+            if dep_unit.target.kind == TargetKind::CustomBuild {
+                continue;
+            }
+            if let Some(path) = &artifacts[dep.index].extern_path {
+                let prefix = if dep.noprelude { "noprelude:" } else { "" };
+                args.push("--extern".to_string());
+                args.push(format!("{prefix}{}={path}", dep.extern_crate_name));
+            }
+        }
+        if !unit.is_local() {
+            args.push("--cap-lints".to_string());
+            args.push("allow".to_string());
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cfg_env_groups_values() {
+        let cfgs = [
+            "debug_assertions",
+            "target_arch=\"x86_64\"",
+            "target_feature=\"fxsr\"",
+            "target_feature=\"sse\"",
+            "unix",
+        ]
+        .map(ToString::to_string);
+        let env = cfg_env(&cfgs, false);
+        assert_eq!(env["CARGO_CFG_TARGET_ARCH"], "x86_64");
+        assert_eq!(env["CARGO_CFG_TARGET_FEATURE"], "fxsr,sse");
+        assert_eq!(env["CARGO_CFG_UNIX"], "");
+        assert!(!env.contains_key("CARGO_CFG_DEBUG_ASSERTIONS"));
+    }
+}